@@ -0,0 +1,156 @@
+//! Rendering without a window
+//!
+//! Useful for producing images on build servers, in batch
+//! jobs, and in tests. Frames are rendered into an
+//! offscreen texture and read back as [`Pixels`]. When no
+//! GPU is available, a software adapter is used if the
+//! platform provides one.
+//!
+//! ```no_run
+//! use peach::headless::Headless;
+//! use peach::prelude::*;
+//!
+//! let settings = Settings {
+//!     size: [256.0, 256.0].into(),
+//!     ..Default::default()
+//! };
+//!
+//! let mut headless = Headless::new(settings).unwrap();
+//! headless.sketch().set_clear_color(colors::WHITE);
+//!
+//! let pixels = headless
+//!     .render(|sketch, gfx| {
+//!         gfx.fill(colors::RED);
+//!         gfx.square(sketch.get_center(), 64.0);
+//!     })
+//!     .unwrap();
+//! ```
+
+use anyhow::Result;
+
+use crate::render::{Graphics, Pixels, RendererInitError};
+use crate::sketch::{Handler, Settings, Sketch};
+
+/// A sketch which renders into an offscreen texture instead
+/// of a window.
+pub struct Headless {
+    sketch: Sketch,
+}
+
+impl Headless {
+    /// Create a headless sketch. The canvas size is taken
+    /// from `settings.size`, rounded to whole pixels and at
+    /// least one pixel wide and tall.
+    pub fn new(settings: Settings) -> Result<Self, RendererInitError> {
+        let sketch = Sketch::headless(settings)?;
+        Ok(Self { sketch })
+    }
+
+    pub fn sketch(&mut self) -> &mut Sketch {
+        &mut self.sketch
+    }
+
+    /// Run `H::setup` against the headless sketch.
+    pub fn setup<H: Handler>(&mut self) -> H {
+        H::setup(&mut self.sketch)
+    }
+
+    /// Render a single frame with `draw`, and read it back.
+    /// The frame is also recorded, if a recording is in
+    /// progress.
+    pub fn render<F>(&mut self, draw: F) -> Result<Pixels>
+    where
+        F: FnOnce(&mut Sketch, &mut Graphics),
+    {
+        let mut gfx = Graphics::new(self.sketch.get_clear_color());
+
+        draw(&mut self.sketch, &mut gfx);
+        self.sketch.record_commands(&gfx)?;
+        self.sketch.renderer.draw(gfx)?;
+        self.sketch.renderer.end_frame();
+        self.sketch.frame_count += 1;
+
+        let pixels = self.sketch.renderer.read_pixels()?;
+        self.sketch.record_pixels(&pixels)?;
+
+        Ok(pixels)
+    }
+
+    /// Render a single frame with `draw` at `scale` times
+    /// the canvas size, for print output. The frame isn't
+    /// counted or recorded, and the canvas is left
+    /// untouched.
+    pub fn render_scaled<F>(&mut self, scale: u32, draw: F) -> Result<Pixels>
+    where
+        F: FnOnce(&mut Sketch, &mut Graphics),
+    {
+        let mut gfx = Graphics::new(self.sketch.get_clear_color());
+
+        draw(&mut self.sketch, &mut gfx);
+        Ok(self.sketch.renderer.render_scaled(gfx, scale)?)
+    }
+
+    /// Render a single frame of `handler`, and read it
+    /// back.
+    pub fn draw<H: Handler>(&mut self, handler: &mut H) -> Result<Pixels> {
+        self.render(|sketch, gfx| handler.draw(sketch, gfx))
+    }
+}
+
+/// Set up `H` and render `frames` frames of it without a
+/// window, returning the pixels of every frame.
+pub fn run<H: Handler>(settings: Settings, frames: usize) -> Result<Vec<Pixels>> {
+    let mut headless = Headless::new(settings)?;
+    let mut handler = headless.setup::<H>();
+
+    let mut output = Vec::with_capacity(frames);
+    for _ in 0..frames {
+        output.push(headless.draw(&mut handler)?);
+    }
+
+    handler.quit();
+    headless.sketch().stop_recording()?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Backend;
+
+    #[test]
+    fn empty_sizes_are_one_pixel() {
+        for size in [[0.0, 0.0], [0.4, 12.0], [-3.0, 0.2]] {
+            let settings = Settings {
+                size: size.into(),
+                backend: Backend::Software,
+                ..Default::default()
+            };
+
+            let mut headless = Headless::new(settings).unwrap();
+            let pixels = headless.render(|_, _| {}).unwrap();
+            assert_eq!(pixels.width(), 1);
+            assert!(pixels.height() >= 1);
+        }
+    }
+
+    #[test]
+    fn oversized_scales_are_an_error() {
+        let settings = Settings {
+            size: [64.0, 64.0].into(),
+            backend: Backend::Software,
+            ..Default::default()
+        };
+
+        let mut headless = Headless::new(settings).unwrap();
+        assert!(headless.render_scaled(u32::MAX / 32, |_, _| {}).is_err());
+        // Each side fits in a `u32`, but the frame would
+        // need 2^64 bytes.
+        assert!(headless.render_scaled(1 << 25, |_, _| {}).is_err());
+        assert!(headless.render_scaled(1024, |_, _| {}).is_err());
+
+        let pixels = headless.render_scaled(2, |_, _| {}).unwrap();
+        assert_eq!((pixels.width(), pixels.height()), (128, 128));
+    }
+}
@@ -1,5 +1,7 @@
+pub mod headless;
 pub mod render;
 pub mod sketch;
+pub mod testing;
 pub mod types;
 
 pub use {lyon_tessellation as tess, rgb};
@@ -26,7 +28,7 @@ pub mod prelude {
     }
 }
 
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 use anyhow::Result;
 use winit::dpi::LogicalSize;
@@ -54,39 +56,51 @@ pub fn run<H: 'static + Handler>(settings: Settings) -> Result<()> {
     let mut sketch = Sketch::new(window, settings);
     let mut handler = H::setup(&mut sketch);
 
-    let mut last_draw_time = Instant::now();
-    let mut frame_delay = Duration::default();
-
     event_loop.run(move |event, _, control_flow| {
         match event {
             Event::MainEventsCleared => {
-                if sketch.framerate_dirty {
-                    if let Some(fps) = sketch.framerate {
-                        frame_delay = Duration::from_secs(1) / fps;
-                    }
-                    sketch.framerate_dirty = false;
+                // Recordings run on a simulated clock, so there's
+                // no need to wait between frames.
+                let wake = if sketch.is_recording() {
+                    None
                 }
-
-                let delta = last_draw_time.elapsed();
-                let draw = match sketch.framerate {
-                    Some(_) => delta >= frame_delay,
-                    None => true,
+                else {
+                    sketch.pacer.wait(Instant::now())
                 };
 
-                if draw {
-                    sketch.window.request_redraw();
-                }
+                // Sleep until the next frame is due, unless
+                // woken sooner by input.
+                *control_flow = match wake {
+                    Some(wake) => ControlFlow::WaitUntil(wake),
+                    None => {
+                        if let Some(window) = &sketch.window {
+                            window.request_redraw();
+                        }
+                        ControlFlow::Poll
+                    },
+                };
             },
             Event::RedrawRequested(..) => {
+                sketch.pacer.start_frame(Instant::now());
+
                 let mut gfx = Graphics::new(sketch.get_clear_color());
 
                 handler.draw(&mut sketch, &mut gfx);
+                if let Err(err) = sketch.record_commands(&gfx) {
+                    sketch.recording_failed(err);
+                }
                 sketch.renderer.render(gfx).unwrap();
-
-                last_draw_time = Instant::now();
+                if let Err(err) = sketch.record_frame() {
+                    sketch.recording_failed(err);
+                }
+                sketch.frame_count += 1;
             },
             Event::LoopDestroyed => {
                 handler.quit();
+                // The process exits once the loop is destroyed,
+                // so a sketch which wants to know whether its
+                // recording was saved should stop it itself.
+                let _ = sketch.stop_recording();
             },
             Event::WindowEvent { event, .. } => sketch.handle_event(&mut handler, event),
             _ => {},
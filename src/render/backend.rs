@@ -0,0 +1,190 @@
+//! Selecting between the GPU renderer and the CPU
+//! rasterizer
+
+use winit::dpi::PhysicalSize;
+use winit::window::Window;
+
+use super::cache::TessellationStats;
+use super::graphics::Graphics;
+use super::image::{Image, Sampling};
+use super::pixels::Pixels;
+use super::renderer::{PresentMode, RenderError, Renderer, RendererInitError};
+use super::software::Rasterizer;
+
+/// Which backend renders the sketch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Render with wgpu.
+    #[default]
+    Gpu,
+    /// Rasterize on the CPU. Output is identical on every
+    /// machine, and no GPU is needed when running headless.
+    /// Windowed sketches still use the GPU to present the
+    /// finished frame.
+    Software,
+}
+
+pub(crate) enum RenderBackend {
+    Gpu(Renderer),
+    Software {
+        rasterizer: Rasterizer,
+        // Only used to present frames to a window.
+        presenter: Option<Renderer>,
+    },
+}
+
+impl RenderBackend {
+    pub async fn new(
+        backend: Backend,
+        window: Option<&Window>,
+        size: PhysicalSize<u32>,
+        default_sampling: Sampling,
+        msaa_samples: u32,
+        present_mode: PresentMode,
+    ) -> Result<Self, RendererInitError> {
+        Ok(match backend {
+            Backend::Gpu => {
+                Self::Gpu(
+                    Renderer::new(window, size, default_sampling, msaa_samples, present_mode)
+                        .await?,
+                )
+            },
+            Backend::Software => {
+                // The presenter only copies finished frames, so
+                // it never needs multisampling.
+                let presenter = match window {
+                    Some(window) => {
+                        Some(
+                            Renderer::new(Some(window), size, default_sampling, 1, present_mode)
+                                .await?,
+                        )
+                    },
+                    None => None,
+                };
+
+                Self::Software {
+                    rasterizer: Rasterizer::new(size, default_sampling),
+                    presenter,
+                }
+            },
+        })
+    }
+
+    pub fn size(&self) -> PhysicalSize<u32> {
+        match self {
+            Self::Gpu(renderer) => renderer.size(),
+            Self::Software { rasterizer, .. } => rasterizer.size(),
+        }
+    }
+
+    pub fn tessellation_stats(&self) -> TessellationStats {
+        match self {
+            Self::Gpu(renderer) => renderer.tessellation_stats(),
+            Self::Software { rasterizer, .. } => rasterizer.tessellation_stats(),
+        }
+    }
+
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        match self {
+            Self::Gpu(renderer) => renderer.resize(size),
+            Self::Software {
+                rasterizer,
+                presenter,
+            } => {
+                rasterizer.resize(size);
+                if let Some(presenter) = presenter {
+                    presenter.resize(size);
+                }
+            },
+        }
+    }
+
+    pub fn set_present_mode(&mut self, mode: PresentMode) {
+        match self {
+            Self::Gpu(renderer) => renderer.set_present_mode(mode),
+            Self::Software {
+                presenter: Some(presenter),
+                ..
+            } => presenter.set_present_mode(mode),
+            Self::Software {
+                presenter: None, ..
+            } => {},
+        }
+    }
+
+    /// Draw `gfx` into the canvas, present the result and
+    /// finish the frame.
+    pub fn render(&mut self, gfx: Graphics) -> Result<(), RenderError> {
+        self.draw(gfx)?;
+        self.present()?;
+        self.end_frame();
+        Ok(())
+    }
+
+    /// Finish a frame, so caches only age once per frame
+    /// however many times they are drawn with.
+    pub fn end_frame(&mut self) {
+        match self {
+            Self::Gpu(renderer) => renderer.end_frame(),
+            Self::Software { rasterizer, .. } => rasterizer.end_frame(),
+        }
+    }
+
+    pub fn draw(&mut self, gfx: Graphics) -> Result<(), RenderError> {
+        match self {
+            Self::Gpu(renderer) => renderer.draw(gfx),
+            Self::Software { rasterizer, .. } => rasterizer.draw(gfx),
+        }
+    }
+
+    pub fn draw_offscreen(&mut self, image: &Image, gfx: Graphics) -> Result<(), RenderError> {
+        match self {
+            Self::Gpu(renderer) => renderer.draw_offscreen(image, gfx),
+            Self::Software { rasterizer, .. } => rasterizer.draw_offscreen(image, gfx),
+        }
+    }
+
+    pub fn render_scaled(&mut self, gfx: Graphics, scale: u32) -> Result<Pixels, RenderError> {
+        match self {
+            Self::Gpu(renderer) => renderer.render_scaled(gfx, scale),
+            Self::Software { rasterizer, .. } => rasterizer.render_scaled(gfx, scale),
+        }
+    }
+
+    pub fn present(&mut self) -> Result<(), RenderError> {
+        match self {
+            Self::Gpu(renderer) => renderer.present(),
+            Self::Software {
+                rasterizer,
+                presenter: Some(presenter),
+            } => {
+                presenter.write_pixels(&rasterizer.read_pixels()?)?;
+                presenter.present()
+            },
+            Self::Software {
+                presenter: None, ..
+            } => Ok(()),
+        }
+    }
+
+    pub fn read_pixels(&self) -> Result<Pixels, RenderError> {
+        match self {
+            Self::Gpu(renderer) => renderer.read_pixels(),
+            Self::Software { rasterizer, .. } => rasterizer.read_pixels(),
+        }
+    }
+
+    pub fn write_pixels(&mut self, pixels: &Pixels) -> Result<(), RenderError> {
+        match self {
+            Self::Gpu(renderer) => renderer.write_pixels(pixels),
+            Self::Software { rasterizer, .. } => rasterizer.write_pixels(pixels),
+        }
+    }
+
+    pub fn read_offscreen_pixels(&mut self, image: &Image) -> Result<Pixels, RenderError> {
+        match self {
+            Self::Gpu(renderer) => renderer.read_offscreen_pixels(image),
+            Self::Software { rasterizer, .. } => rasterizer.read_offscreen_pixels(image),
+        }
+    }
+}
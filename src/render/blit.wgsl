@@ -0,0 +1,31 @@
+// Copies the canvas texture onto the window surface with a
+// single triangle covering the whole screen.
+
+[[group(0), binding(0)]]
+var canvas_texture: texture_2d<f32>;
+[[group(0), binding(1)]]
+var canvas_sampler: sampler;
+
+struct VertexOutput {
+    [[location(0)]] uv: vec2<f32>;
+    [[builtin(position)]] position: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn vs_main(
+    [[builtin(vertex_index)]] index: u32,
+) -> VertexOutput {
+    var out: VertexOutput;
+
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+
+    out.uv = uv;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+
+    return out;
+}
+
+[[stage(fragment)]]
+fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return textureSample(canvas_texture, canvas_sampler, in.uv);
+}
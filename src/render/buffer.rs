@@ -0,0 +1,77 @@
+//! GPU buffers which are reused between frames
+
+/// Smallest buffer created, so that sketches drawing a few
+/// shapes never need to grow.
+const MIN_CAPACITY: wgpu::BufferAddress = 64 * 1024;
+
+/// A GPU buffer which keeps its allocation between frames,
+/// and is only replaced when a frame needs more space than
+/// it has. Capacity at least doubles each time it grows, so
+/// a sketch drawing more and more settles after a few
+/// frames.
+pub(crate) struct GrowableBuffer {
+    label: &'static str,
+    usage: wgpu::BufferUsages,
+    capacity: wgpu::BufferAddress,
+    buffer: wgpu::Buffer,
+}
+
+impl GrowableBuffer {
+    pub fn new(device: &wgpu::Device, label: &'static str, usage: wgpu::BufferUsages) -> Self {
+        let usage = usage | wgpu::BufferUsages::COPY_DST;
+
+        Self {
+            label,
+            usage,
+            capacity: MIN_CAPACITY,
+            buffer: Self::create(device, label, usage, MIN_CAPACITY),
+        }
+    }
+
+    fn create(
+        device: &wgpu::Device,
+        label: &str,
+        usage: wgpu::BufferUsages,
+        capacity: wgpu::BufferAddress,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: capacity,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Replace the contents of the buffer with `data`,
+    /// growing it first if needed. The write happens before
+    /// the next submission, after any earlier ones, so
+    /// work already submitted still sees the old contents.
+    ///
+    /// Returns whether the buffer was replaced, in which
+    /// case bind groups using it have to be recreated.
+    pub fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, data: &[u8]) -> bool {
+        let len = data.len() as wgpu::BufferAddress;
+        // Only ever written with slices of 32-bit values.
+        debug_assert_eq!(len % wgpu::COPY_BUFFER_ALIGNMENT, 0);
+
+        let grow = len > self.capacity;
+        if grow {
+            self.capacity = len.next_power_of_two().max(self.capacity * 2);
+            self.buffer = Self::create(device, self.label, self.usage, self.capacity);
+        }
+
+        if !data.is_empty() {
+            queue.write_buffer(&self.buffer, 0, data);
+        }
+
+        grow
+    }
+
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..)
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+}
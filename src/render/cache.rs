@@ -0,0 +1,278 @@
+//! Reusing tessellated shapes between frames
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+
+use super::context::Context;
+use crate::tess;
+use crate::tess::path::iterator::FromPolyline;
+use crate::types::Point;
+
+/// Shapes which go unused for this many frames are evicted.
+const MAX_AGE: u64 = 120;
+
+/// Most shapes cached at once. Once full, new shapes are
+/// tessellated without being cached until older ones are
+/// evicted.
+const MAX_ENTRIES: usize = 4096;
+
+pub(crate) type Mesh = tess::VertexBuffers<tess::math::Point, u32>;
+pub(crate) type StrokeMesh = tess::VertexBuffers<StrokePoint, u32>;
+
+/// A stroke vertex, kept on the path so the stroke can be
+/// extruded to any width.
+#[derive(Clone, Copy)]
+pub(crate) struct StrokePoint {
+    pub position: tess::math::Point,
+    /// Moving the vertex along this by half the stroke
+    /// weight puts it on the stroke's edge.
+    pub normal: tess::math::Vector,
+    /// 1 on the left of the path and -1 on the right.
+    pub side: f32,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct ShapeKey {
+    closed: bool,
+    fill: bool,
+    // Bits of the stroke weight, when stroked.
+    stroke: Option<u32>,
+    // Bits of each point, relative to the first.
+    points: Vec<[u32; 2]>,
+}
+
+/// Fill and stroke meshes of a shape, relative to its first
+/// point. Colors are left out, so they can change without
+/// re-tessellating.
+#[derive(Default)]
+pub(crate) struct CachedShape {
+    pub fill: Option<Mesh>,
+    pub stroke: Option<StrokeMesh>,
+    last_used: u64,
+}
+
+/// How well tessellated shapes are being reused between
+/// frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TessellationStats {
+    /// Shapes drawn using a cached mesh.
+    pub hits: u64,
+    /// Shapes which had to be tessellated.
+    pub misses: u64,
+    /// Cached shapes dropped after going unused.
+    pub evictions: u64,
+    /// Shapes currently cached.
+    pub entries: usize,
+}
+
+impl TessellationStats {
+    /// Fraction of shapes drawn from the cache, or `0.0`
+    /// before anything has been drawn.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        }
+        else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Tessellated shapes, keyed by their points and the fill
+/// and stroke options used. Shapes are cached relative to
+/// their first point, and transforms are applied on the
+/// GPU, so moving or rotating a shape still reuses it.
+#[derive(Default)]
+pub(crate) struct TessellationCache {
+    shapes: HashMap<ShapeKey, CachedShape>,
+    // Holds shapes which didn't fit in a full cache.
+    overflow: CachedShape,
+
+    fill_tess: tess::FillTessellator,
+    stroke_tess: tess::StrokeTessellator,
+
+    generation: u64,
+    stats: TessellationStats,
+
+    // Caches for each thread tessellating part of a frame.
+    #[cfg(feature = "parallel")]
+    workers: Vec<TessellationCache>,
+}
+
+impl TessellationCache {
+    pub fn stats(&self) -> TessellationStats {
+        let stats = TessellationStats {
+            entries: self.shapes.len(),
+            ..self.stats
+        };
+
+        #[cfg(feature = "parallel")]
+        let stats = self.workers.iter().map(Self::stats).fold(stats, |a, b| {
+            TessellationStats {
+                hits: a.hits + b.hits,
+                misses: a.misses + b.misses,
+                evictions: a.evictions + b.evictions,
+                entries: a.entries + b.entries,
+            }
+        });
+
+        stats
+    }
+
+    /// Separate caches for `count` threads. Each thread
+    /// keeps the same cache between frames, so shapes drawn
+    /// in the same part of each frame are still reused.
+    #[cfg(feature = "parallel")]
+    pub fn workers(&mut self, count: usize) -> &mut [TessellationCache] {
+        if self.workers.len() < count {
+            self.workers.resize_with(count, Default::default);
+        }
+        &mut self.workers[..count]
+    }
+
+    /// Meshes for the polyline through `points`, filled and
+    /// stroked with the options of `context`, along with
+    /// the point they are relative to.
+    pub fn get(
+        &mut self,
+        closed: bool,
+        points: &[Point],
+        context: &Context,
+    ) -> Result<(&CachedShape, Point), tess::TessellationError> {
+        let origin = points.first().copied().unwrap_or_else(Point::origin);
+        let relative = points
+            .iter()
+            .map(|point| (*point - origin).cast::<f32>().to_point());
+
+        let key = ShapeKey {
+            closed,
+            fill: context.fill.is_some(),
+            stroke: context.stroke.map(|_| context.stroke_weight.to_bits()),
+            points: relative
+                .clone()
+                .map(|point| [point.x.to_bits(), point.y.to_bits()])
+                .collect(),
+        };
+
+        let Self {
+            shapes,
+            overflow,
+            fill_tess,
+            stroke_tess,
+            generation,
+            stats,
+            ..
+        } = self;
+        let full = shapes.len() >= MAX_ENTRIES;
+
+        let shape = match shapes.entry(key) {
+            Entry::Occupied(entry) => {
+                stats.hits += 1;
+                entry.into_mut()
+            },
+            Entry::Vacant(entry) => {
+                stats.misses += 1;
+
+                let mut shape = CachedShape::default();
+                if context.fill.is_some() {
+                    let mut mesh = Mesh::new();
+                    fill_tess.tessellate(
+                        FromPolyline::new(closed, relative.clone()),
+                        &context.get_fill_options(),
+                        &mut tess::BuffersBuilder::new(
+                            &mut mesh,
+                            tess::geometry_builder::Positions,
+                        ),
+                    )?;
+                    shape.fill = Some(mesh);
+                }
+                if context.stroke.is_some() {
+                    let mut mesh = StrokeMesh::new();
+                    stroke_tess.tessellate(
+                        FromPolyline::new(closed, relative),
+                        &context.get_stroke_options(),
+                        &mut tess::BuffersBuilder::new(&mut mesh, |vertex: tess::StrokeVertex| {
+                            StrokePoint {
+                                position: vertex.position_on_path(),
+                                normal: vertex.normal(),
+                                side: match vertex.side() {
+                                    tess::Side::Left => 1.0,
+                                    tess::Side::Right => -1.0,
+                                },
+                            }
+                        }),
+                    )?;
+                    shape.stroke = Some(mesh);
+                }
+
+                if full {
+                    *overflow = shape;
+                    overflow
+                }
+                else {
+                    entry.insert(shape)
+                }
+            },
+        };
+        shape.last_used = *generation;
+
+        Ok((shape, origin))
+    }
+
+    /// Finish a frame, evicting shapes which have gone
+    /// unused for too long. Everything built in between,
+    /// such as canvases and tiles, counts as the same
+    /// frame.
+    pub fn end_frame(&mut self) {
+        self.generation += 1;
+
+        let generation = self.generation;
+        let before = self.shapes.len();
+        self.shapes
+            .retain(|_, shape| generation - shape.last_used <= MAX_AGE);
+
+        self.stats.evictions += (before - self.shapes.len()) as u64;
+        self.overflow = CachedShape::default();
+
+        #[cfg(feature = "parallel")]
+        self.workers.iter_mut().for_each(Self::end_frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square() -> Vec<Point> {
+        vec![
+            Point::new(0.0, 0.0),
+            Point::new(1.0, 0.0),
+            Point::new(1.0, 1.0),
+            Point::new(0.0, 1.0),
+        ]
+    }
+
+    #[test]
+    fn shapes_only_age_once_per_frame() {
+        let mut cache = TessellationCache::default();
+        let context = Context::default();
+
+        // Canvases and tiles drawn within a frame don't age
+        // the cache.
+        for _ in 0..MAX_AGE * 2 {
+            cache.get(true, &square(), &context).unwrap();
+        }
+
+        for _ in 0..MAX_AGE {
+            cache.end_frame();
+        }
+        assert_eq!(cache.stats().entries, 1);
+
+        cache.end_frame();
+        let stats = cache.stats();
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.entries, 0);
+        assert_eq!(stats.hits, MAX_AGE * 2 - 1);
+    }
+}
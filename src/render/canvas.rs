@@ -0,0 +1,54 @@
+//! Offscreen drawing surfaces
+
+use super::graphics::Graphics;
+use super::image::Image;
+use crate::types::{Color, Size};
+
+/// An offscreen surface with its own size, similar to
+/// Processing's `createGraphics`.
+///
+/// Commands recorded into [`Canvas::graphics`] are rendered
+/// with [`Sketch::render_canvas`], and the result can then
+/// be drawn like any other image using [`Canvas::image`].
+/// The contents are kept between renders, which makes
+/// canvases useful for trails, layers and caching expensive
+/// backgrounds.
+///
+/// [`Sketch::render_canvas`]: crate::sketch::Sketch::render_canvas
+#[derive(Clone)]
+pub struct Canvas {
+    image: Image,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            image: Image::render_target(width.max(1), height.max(1)),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.image.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.image.height()
+    }
+
+    pub fn size(&self) -> Size {
+        self.image.size()
+    }
+
+    /// The canvas contents, for drawing with
+    /// [`Graphics::image`].
+    pub fn image(&self) -> &Image {
+        &self.image
+    }
+
+    /// Start a new command stream for a canvas. When
+    /// `clear_color` is `None`, drawing happens on top of
+    /// the canvas's previous contents.
+    pub fn graphics(clear_color: Option<Color>) -> Graphics {
+        Graphics::new(clear_color)
+    }
+}
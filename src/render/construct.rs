@@ -1,34 +1,107 @@
 //! Tessallation constructors
 
-use super::context::Context;
+use std::ops::Range;
+
+use super::cache::{Mesh, StrokeMesh};
+use super::context::{Context, StrokeMode};
+use super::image::Image;
+use super::shape::Shape;
 use crate::tess;
-use crate::types::{Color, GpuScalar, Index, Point, RawVertex};
+use crate::types::{
+    BoundingBox, Color, GpuScalar, Index, Point, RawVertex, Scalar, Transform, Vector,
+};
 
 type GeometryBuilderResult = Result<tess::VertexId, tess::GeometryBuilderError>;
 
+/// A range of indices which are all drawn with the same
+/// image bound. Untextured geometry has no image.
+#[derive(Clone)]
+pub(crate) struct Batch {
+    pub image: Option<Image>,
+    pub indices: Range<Index>,
+    /// Instances to draw the indices with.
+    pub instances: Range<u32>,
+    /// A shape drawn from its own retained buffers instead
+    /// of `indices`, which are left empty.
+    pub shape: Option<Shape>,
+}
+
+impl Batch {
+    fn uses(&self, image: Option<&Image>) -> bool {
+        let same_image = match (&self.image, image) {
+            (None, None) => true,
+            (Some(a), Some(b)) => a.id() == b.id() && a.sampling() == b.sampling(),
+            _ => false,
+        };
+
+        same_image && self.shape.is_none()
+    }
+
+    /// Whether `next` directly follows this batch, and can
+    /// be drawn in the same call.
+    #[cfg(feature = "parallel")]
+    pub fn continues_with(&self, next: &Batch) -> bool {
+        self.indices.end == next.indices.start
+            && next.shape.is_none()
+            && self.uses(next.image.as_ref())
+    }
+}
+
 #[derive(Default)]
 pub(super) struct RawBuffersBuilder {
     vertices: Vec<RawVertex>,
     indices: Vec<Index>,
+    batches: Vec<Batch>,
 
     offsets: (Index, Index),
 
     context: Context,
+    // Vertices are left untransformed, and refer to one of
+    // these instead. Only transforms which are used are
+    // kept, and `transform` is the index of the current one,
+    // once a vertex has used it.
+    transforms: Vec<Transform>,
+    transform: Option<u32>,
 }
 
 impl RawBuffersBuilder {
     fn add_vertex(&mut self, position: Point, color: Color) -> GeometryBuilderResult {
+        self.add_textured_vertex(position, color, Point::zero())
+    }
+
+    fn add_textured_vertex(
+        &mut self,
+        position: Point,
+        color: Color,
+        uv: Point,
+    ) -> GeometryBuilderResult {
+        self.push_vertex(position, color, uv, Vector::zero(), [0.0; 2])
+    }
+
+    fn push_vertex(
+        &mut self,
+        position: Point,
+        color: Color,
+        uv: Point,
+        normal: Vector,
+        stroke: [GpuScalar; 2],
+    ) -> GeometryBuilderResult {
         use rgb::ComponentMap;
 
-        let position: [GpuScalar; 2] = self
-            .context
-            .transform
-            .transform_point(position)
-            .cast()
-            .into();
+        let position: [GpuScalar; 2] = position.cast().into();
         let color: [GpuScalar; 4] = color.map(|p| p as GpuScalar).into();
+        let uv: [GpuScalar; 2] = uv.cast().into();
+        let normal: [GpuScalar; 2] = normal.cast().into();
+        let transform = self.transform_index();
 
-        self.vertices.push(RawVertex { position, color });
+        self.vertices.push(RawVertex {
+            position,
+            color,
+            uv,
+            transform,
+            normal,
+            stroke,
+        });
 
         let len = self.vertices.len();
         if len > Index::MAX as usize {
@@ -39,12 +112,202 @@ impl RawBuffersBuilder {
         Ok(tess::VertexId(id))
     }
 
+    fn transform_index(&mut self) -> u32 {
+        match self.transform {
+            Some(index) => index,
+            None => {
+                self.transforms.push(self.context.transform);
+                let index = self.transforms.len() as u32 - 1;
+                self.transform = Some(index);
+                index
+            },
+        }
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
     pub fn set_context(&mut self, context: Context) {
+        if context.transform != self.context.transform {
+            self.transform = None;
+        }
         self.context = context;
     }
 
-    pub fn take(self) -> (Vec<RawVertex>, Vec<Index>) {
-        (self.vertices, self.indices)
+    /// Begin a new batch if the bound image differs from
+    /// the current batch.
+    pub fn set_image(&mut self, image: Option<&Image>) {
+        if let Some(batch) = self.batches.last() {
+            if batch.uses(image) {
+                return;
+            }
+        }
+
+        self.end_batch();
+        let start = self.indices.len() as Index;
+        self.batches.push(Batch {
+            image: image.cloned(),
+            indices: start..start,
+            instances: 0..1,
+            shape: None,
+        });
+    }
+
+    /// Draw `shape` from its retained buffers, once for
+    /// each of `instances`.
+    pub fn add_shape(&mut self, shape: &Shape, instances: Range<u32>) {
+        self.end_batch();
+        let start = self.indices.len() as Index;
+        self.batches.push(Batch {
+            image: None,
+            indices: start..start,
+            instances,
+            shape: Some(shape.clone()),
+        });
+    }
+
+    fn end_batch(&mut self) {
+        let end = self.indices.len() as Index;
+        if let Some(batch) = self.batches.last_mut() {
+            if batch.shape.is_none() {
+                batch.indices.end = end;
+            }
+        }
+    }
+
+    /// Add a textured quad covering `rect`, which samples
+    /// `uv` from the currently bound image.
+    pub fn add_image_quad(
+        &mut self,
+        rect: BoundingBox,
+        uv: BoundingBox,
+    ) -> Result<(), tess::GeometryBuilderError> {
+        use tess::GeometryBuilder;
+
+        let tint = self.context.tint;
+
+        self.begin_geometry();
+
+        let corners = [
+            (rect.min, uv.min),
+            (
+                Point::new(rect.max.x, rect.min.y),
+                Point::new(uv.max.x, uv.min.y),
+            ),
+            (rect.max, uv.max),
+            (
+                Point::new(rect.min.x, rect.max.y),
+                Point::new(uv.min.x, uv.max.y),
+            ),
+        ];
+
+        let mut ids = [tess::VertexId(0); 4];
+        for (id, (position, uv)) in ids.iter_mut().zip(corners) {
+            *id = match self.add_textured_vertex(position, tint, uv) {
+                Ok(id) => id,
+                Err(err) => {
+                    self.abort_geometry();
+                    return Err(err);
+                },
+            };
+        }
+
+        self.add_triangle(ids[0], ids[1], ids[2]);
+        self.add_triangle(ids[0], ids[2], ids[3]);
+        self.end_geometry();
+
+        Ok(())
+    }
+
+    /// Add a cached mesh, offset by `origin` and drawn in
+    /// `color`.
+    pub fn add_mesh(
+        &mut self,
+        mesh: &Mesh,
+        origin: Point,
+        color: Color,
+    ) -> Result<(), tess::GeometryBuilderError> {
+        use tess::GeometryBuilder;
+
+        self.begin_geometry();
+
+        for vertex in mesh.vertices.iter() {
+            let position = origin + vertex.cast().to_vector();
+            if let Err(err) = self.add_vertex(position, color) {
+                self.abort_geometry();
+                return Err(err);
+            }
+        }
+
+        let offset = self.offsets.0;
+        self.indices
+            .extend(mesh.indices.iter().map(|index| index + offset));
+        self.end_geometry();
+
+        Ok(())
+    }
+
+    /// Add a cached stroke mesh, offset by `origin` and
+    /// drawn in `color` with the current stroke weight and
+    /// mode.
+    pub fn add_stroke_mesh(
+        &mut self,
+        mesh: &StrokeMesh,
+        origin: Point,
+        color: Color,
+    ) -> Result<(), tess::GeometryBuilderError> {
+        use tess::GeometryBuilder;
+
+        let half_width = self.context.stroke_weight / 2.0;
+        let mode = self.context.stroke_mode;
+
+        self.begin_geometry();
+
+        for vertex in mesh.vertices.iter() {
+            let position = origin + vertex.position.cast().to_vector();
+            let normal = vertex.normal.cast::<Scalar>();
+
+            // Anti-aliased strokes are extruded in the vertex
+            // shader, once the pixel size is known.
+            let result = match mode {
+                StrokeMode::Standard => {
+                    self.add_vertex(position + normal * half_width as Scalar, color)
+                },
+                StrokeMode::Smooth => {
+                    let stroke = [vertex.side, half_width].map(|x| x as GpuScalar);
+                    self.push_vertex(position, color, Point::zero(), normal, stroke)
+                },
+                StrokeMode::Hairline => {
+                    let stroke = [vertex.side, -1.0].map(|x| x as GpuScalar);
+                    self.push_vertex(position, color, Point::zero(), normal, stroke)
+                },
+            };
+
+            if let Err(err) = result {
+                self.abort_geometry();
+                return Err(err);
+            }
+        }
+
+        let offset = self.offsets.0;
+        self.indices
+            .extend(mesh.indices.iter().map(|index| index + offset));
+        self.end_geometry();
+
+        Ok(())
+    }
+
+    pub fn index_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn take(mut self) -> (Vec<RawVertex>, Vec<Index>, Vec<Batch>, Vec<Transform>) {
+        self.end_batch();
+        self.batches
+            .retain(|batch| batch.shape.is_some() || !batch.indices.is_empty());
+
+        (self.vertices, self.indices, self.batches, self.transforms)
     }
 }
 
@@ -79,27 +342,3 @@ impl tess::GeometryBuilder for RawBuffersBuilder {
         self.indices.truncate(self.offsets.1 as usize);
     }
 }
-
-impl tess::FillGeometryBuilder for RawBuffersBuilder {
-    fn add_fill_vertex(&mut self, vertex: tess::FillVertex) -> GeometryBuilderResult {
-        let position = vertex.position();
-        if let Some(color) = self.context.fill {
-            self.add_vertex(position.cast(), color)
-        }
-        else {
-            Err(tess::GeometryBuilderError::InvalidVertex)
-        }
-    }
-}
-
-impl tess::StrokeGeometryBuilder for RawBuffersBuilder {
-    fn add_stroke_vertex(&mut self, vertex: tess::StrokeVertex) -> GeometryBuilderResult {
-        let position = vertex.position();
-        if let Some(color) = self.context.stroke {
-            self.add_vertex(position.cast(), color)
-        }
-        else {
-            Err(tess::GeometryBuilderError::InvalidVertex)
-        }
-    }
-}
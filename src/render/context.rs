@@ -2,18 +2,40 @@ use crate::tess;
 use crate::types::{Color, Transform};
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub enum AnchorMode {
     First,
     Center,
 }
 
+/// How strokes are drawn by the GPU renderer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum StrokeMode {
+    /// Tessellated at the stroke weight, with edges only
+    /// smoothed by MSAA.
+    Standard,
+    /// Edges are feathered across a pixel in the fragment
+    /// shader, so thin strokes look even. Strokes thinner
+    /// than a pixel fade out instead of breaking up.
+    Smooth,
+    /// Anti-aliased lines one pixel wide on screen,
+    /// whatever the stroke weight and transform.
+    Hairline,
+}
+
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct Context {
     pub anchor_mode: AnchorMode,
     pub transform: Transform,
     pub fill: Option<Color>,
     pub stroke: Option<Color>,
     pub stroke_weight: f32,
+    pub stroke_mode: StrokeMode,
+    // Multiplied with image colors, and set while drawing
+    // tinted instances.
+    pub tint: Color,
 }
 
 impl Context {
@@ -38,6 +60,8 @@ impl Default for Context {
             fill: Some(Color::new(0.0, 0.0, 0.0, 1.0)),
             stroke: Some(Color::new(0.0, 0.0, 0.0, 1.0)),
             stroke_weight: 1.0,
+            stroke_mode: StrokeMode::Standard,
+            tint: Color::new(1.0, 1.0, 1.0, 1.0),
         }
     }
 }
@@ -0,0 +1,366 @@
+//! Saving and replaying recorded draw commands
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::Path;
+
+use rgb::{ComponentBytes, FromSlice};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::context::Context;
+use super::graphics::{DrawCommand, Graphics};
+use super::image::{Image, Sampling};
+use super::shape;
+use crate::types::{BoundingBox, Color, Point};
+
+#[derive(Error, Debug)]
+pub enum DisplayListError {
+    #[error("Failed to access file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid JSON display list: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid binary display list: {0}")]
+    Binary(#[from] bincode::Error),
+    #[error(
+        "Image {index} is {width}x{height}, which needs {expected} bytes of pixels, not {actual}"
+    )]
+    ImageSize {
+        index: usize,
+        width: u32,
+        height: u32,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Command {command} draws image {index}, but there are only {count} images")]
+    MissingImage {
+        command: usize,
+        index: usize,
+        count: usize,
+    },
+    #[error("Image {index} is {width}x{height}, but images must be at least 1x1")]
+    EmptyImage {
+        index: usize,
+        width: u32,
+        height: u32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImageRecord {
+    width: u32,
+    height: u32,
+    // sRGB encoded RGBA bytes, or `None` for canvas images,
+    // whose pixels only exist on the GPU.
+    pixels: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Command {
+    Draw(bool, Vec<Point>),
+    // Index into `DisplayList::images`, along with the
+    // sampling of the handle which was drawn.
+    Image(usize, Option<Sampling>, BoundingBox, BoundingBox),
+    UpdateContext(Context),
+}
+
+/// The draw commands of a [`Graphics`], in a form which can
+/// be written to disk and replayed later, with any backend.
+///
+/// Images are stored once, however many times they are
+/// drawn. Canvas images have no pixels on the CPU, so they
+/// replay as transparent images of the same size.
+///
+/// Lists can be written as JSON, which is easy to read and
+/// edit for bug reports, or as a compact binary format.
+/// They can also be used with any other serde format. Lists
+/// are checked as they are read, so one with missing or
+/// corrupt images fails to load rather than replaying
+/// something different.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "UncheckedDisplayList")]
+pub struct DisplayList {
+    clear_color: Option<Color>,
+    images: Vec<ImageRecord>,
+    commands: Vec<Command>,
+}
+
+/// A [`DisplayList`] as read, before it has been checked.
+#[derive(Deserialize)]
+struct UncheckedDisplayList {
+    clear_color: Option<Color>,
+    images: Vec<ImageRecord>,
+    commands: Vec<Command>,
+}
+
+impl TryFrom<UncheckedDisplayList> for DisplayList {
+    type Error = DisplayListError;
+
+    fn try_from(list: UncheckedDisplayList) -> Result<Self, Self::Error> {
+        for (index, image) in list.images.iter().enumerate() {
+            if image.width == 0 || image.height == 0 {
+                return Err(DisplayListError::EmptyImage {
+                    index,
+                    width: image.width,
+                    height: image.height,
+                });
+            }
+
+            let expected = 4 * image.width as usize * image.height as usize;
+            match &image.pixels {
+                Some(pixels) if pixels.len() != expected => {
+                    return Err(DisplayListError::ImageSize {
+                        index,
+                        width: image.width,
+                        height: image.height,
+                        expected,
+                        actual: pixels.len(),
+                    });
+                },
+                _ => {},
+            }
+        }
+
+        for (command, draw) in list.commands.iter().enumerate() {
+            match draw {
+                Command::Image(index, ..) if *index >= list.images.len() => {
+                    return Err(DisplayListError::MissingImage {
+                        command,
+                        index: *index,
+                        count: list.images.len(),
+                    });
+                },
+                _ => {},
+            }
+        }
+
+        Ok(Self {
+            clear_color: list.clear_color,
+            images: list.images,
+            commands: list.commands,
+        })
+    }
+}
+
+impl DisplayList {
+    pub(super) fn new(commands: &[DrawCommand], clear_color: Option<Color>) -> Self {
+        let mut images = Vec::new();
+        let mut image_indices = HashMap::new();
+
+        let commands = shape::flatten(commands)
+            .iter()
+            .map(|command| {
+                match command {
+                    DrawCommand::Draw(closed, points) => Command::Draw(*closed, points.clone()),
+                    DrawCommand::Image(image, rect, uv) => {
+                        let index = *image_indices.entry(image.id()).or_insert_with(|| {
+                            images.push(ImageRecord {
+                                width: image.width(),
+                                height: image.height(),
+                                pixels: image
+                                    .to_pixels()
+                                    .map(|pixels| pixels.as_raw().as_bytes().to_vec()),
+                            });
+                            images.len() - 1
+                        });
+
+                        Command::Image(index, image.sampling(), *rect, *uv)
+                    },
+                    DrawCommand::UpdateContext(context) => Command::UpdateContext(*context),
+                    DrawCommand::Instanced(..) => {
+                        unreachable!("instanced draws are flattened first")
+                    },
+                }
+            })
+            .collect();
+
+        Self {
+            clear_color,
+            images,
+            commands,
+        }
+    }
+
+    /// The color the frame was cleared to before drawing,
+    /// if any.
+    pub fn clear_color(&self) -> Option<Color> {
+        self.clear_color
+    }
+
+    /// Number of recorded commands, including context
+    /// changes.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    pub(super) fn draw_commands(&self) -> Vec<DrawCommand> {
+        let images: Vec<Image> = self
+            .images
+            .iter()
+            .map(|record| {
+                match &record.pixels {
+                    Some(bytes) => {
+                        Image::from_rgba8(record.width, record.height, bytes.as_rgba().to_vec())
+                            .expect("image sizes are checked when the list is read")
+                    },
+                    None => Image::new(record.width, record.height),
+                }
+            })
+            .collect();
+
+        self.commands
+            .iter()
+            .map(|command| {
+                match command {
+                    Command::Draw(closed, points) => DrawCommand::Draw(*closed, points.clone()),
+                    Command::Image(index, sampling, rect, uv) => {
+                        let mut image = images[*index].clone();
+                        if let Some(sampling) = sampling {
+                            image.set_sampling(*sampling);
+                        }
+                        DrawCommand::Image(image, *rect, *uv)
+                    },
+                    Command::UpdateContext(context) => DrawCommand::UpdateContext(*context),
+                }
+            })
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Result<String, DisplayListError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, DisplayListError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DisplayListError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DisplayListError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    /// Write the list to `path`, as JSON if the extension
+    /// is `.json`, and in the binary format otherwise.
+    pub fn save<P>(&self, path: P) -> Result<(), DisplayListError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let bytes = if is_json(path) {
+            self.to_json()?.into_bytes()
+        }
+        else {
+            self.to_bytes()?
+        };
+
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Read a list written by [`DisplayList::save`].
+    pub fn load<P>(path: P) -> Result<Self, DisplayListError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path)?;
+
+        if is_json(path) {
+            Ok(serde_json::from_slice(&bytes)?)
+        }
+        else {
+            Self::from_bytes(&bytes)
+        }
+    }
+
+    /// A new [`Graphics`] containing only this list's
+    /// commands, such as for
+    /// [`Graphics::save_svg`](Graphics::save_svg).
+    pub fn to_graphics(&self) -> Graphics {
+        let mut gfx = Graphics::default();
+        gfx.replay(self);
+        gfx
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some(extension) if extension.eq_ignore_ascii_case("json")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{Instance, Shape};
+    use crate::types::Angle;
+
+    fn graphics() -> Graphics {
+        let mut image = Image::new(2, 2).with_sampling(Sampling::pixelated());
+        image.set(1, 0, Color::new(1.0, 0.0, 0.0, 1.0));
+        let dot = Shape::new(|gfx| gfx.square([0.0, 0.0], 4.0));
+
+        let mut gfx = Graphics::new(Some(Color::new(1.0, 1.0, 1.0, 1.0)));
+        gfx.fill(Color::new(0.0, 0.0, 1.0, 0.5));
+        gfx.rect([10.0, 10.0], [20.0, 8.0]);
+        gfx.rotate(Angle::degrees(30.0));
+        gfx.stroke_weight(3.0);
+        gfx.image(&image, [30.0, 30.0], [8.0, 8.0]);
+        gfx.instanced(&dot, &[Instance::at([5.0, 5.0]), Instance::at([15.0, 5.0])]);
+        gfx
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let gfx = graphics();
+        let json = gfx.to_display_list().to_json().unwrap();
+        let list = DisplayList::from_json(&json).unwrap();
+
+        assert_eq!(list.clear_color(), gfx.clear_color);
+        assert_eq!(
+            list.to_graphics().tessellate().unwrap(),
+            gfx.tessellate().unwrap()
+        );
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let gfx = graphics();
+        let bytes = gfx.to_display_list().to_bytes().unwrap();
+        let list = DisplayList::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            list.to_graphics().tessellate().unwrap(),
+            gfx.tessellate().unwrap()
+        );
+    }
+
+    #[test]
+    fn corrupt_images_fail_to_load() {
+        let json = graphics().to_display_list().to_json().unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["images"][0]["pixels"]
+            .as_array_mut()
+            .unwrap()
+            .truncate(7);
+
+        let err = DisplayList::from_json(&value.to_string()).unwrap_err();
+        assert!(err.to_string().contains("needs 16 bytes"), "{}", err);
+
+        value["images"][0]["width"] = 0.into();
+        value["images"][0]["pixels"] = serde_json::json!([]);
+        let err = DisplayList::from_json(&value.to_string()).unwrap_err();
+        assert!(err.to_string().contains("at least 1x1"), "{}", err);
+
+        value["images"] = serde_json::json!([]);
+        assert!(DisplayList::from_json(&value.to_string()).is_err());
+    }
+}
@@ -1,20 +1,75 @@
+use std::path::Path;
+use std::sync::Arc;
+
 use anyhow::Result;
 
-use super::construct::RawBuffersBuilder;
-use super::context::{AnchorMode, Context};
+use super::cache::TessellationCache;
+use super::construct::{Batch, RawBuffersBuilder};
+use super::context::{AnchorMode, Context, StrokeMode};
+#[cfg(feature = "serialize")]
+use super::display_list::DisplayList;
+use super::image::Image;
+#[cfg(feature = "parallel")]
+use super::parallel;
+use super::pdf::PdfDocument;
+use super::save::SaveError;
+use super::shape::{self, Instance, Shape};
+use super::svg;
+use super::tessellation::{CommandGeometry, GeometryKind, Tessellation};
 use crate::tess;
-use crate::tess::path::iterator::FromPolyline;
 use crate::types::*;
 
 #[derive(Clone)]
-enum DrawCommand {
+pub(super) enum DrawCommand {
     Draw(bool, Vec<Point>),
+    Image(Image, BoundingBox, BoundingBox),
     UpdateContext(Context),
+    Instanced(Shape, Arc<[Instance]>),
 }
 
 pub(crate) struct BufferData {
     pub vertices: Vec<RawVertex>,
     pub indices: Vec<Index>,
+    pub batches: Vec<Batch>,
+    /// Transforms referred to by each vertex, applied in
+    /// the vertex shader.
+    pub transforms: Vec<Transform>,
+    /// Per-instance data, starting with the identity
+    /// instance used by everything which isn't instanced.
+    pub instances: Vec<RawInstance>,
+    pub commands: Vec<CommandGeometry>,
+}
+
+impl BufferData {
+    /// Transform every vertex into pixel coordinates on the
+    /// CPU, as the vertex shader does on the GPU.
+    /// Anti-aliased strokes are extruded to their edges,
+    /// without feathering.
+    pub fn apply_transforms(&mut self) {
+        for vertex in self.vertices.iter_mut() {
+            let [x, y] = vertex.position;
+            let transform = &self.transforms[vertex.transform as usize];
+            let mut position = transform.transform_point(Point::new(x as Scalar, y as Scalar));
+
+            let [side, half_width] = vertex.stroke;
+            if side != 0.0 {
+                let [x, y] = vertex.normal;
+                let normal = Vector::new(x as Scalar, y as Scalar);
+                let offset = transform.transform_vector(normal);
+
+                position += if half_width < 0.0 {
+                    // Half a pixel either side, keeping the
+                    // length of mitered normals.
+                    offset.normalize() * normal.length() * 0.5
+                }
+                else {
+                    offset * half_width as Scalar
+                };
+            }
+
+            vertex.position = position.cast().into();
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -25,6 +80,15 @@ pub struct Graphics {
     context_dirty: bool,
 }
 
+impl Default for Graphics {
+    /// An empty `Graphics` which doesn't clear the frame,
+    /// for recording commands outside of a sketch, such as
+    /// to inspect them with [`Graphics::tessellate`].
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
 impl Graphics {
     pub(crate) fn new(clear_color: Option<Color>) -> Self {
         Self {
@@ -91,6 +155,25 @@ impl Graphics {
         self.draw_commands.push(command);
     }
 
+    /// Take every command recorded so far, leaving `self`
+    /// empty but with the same context. The clear color
+    /// only applies to the first batch of commands in a
+    /// frame.
+    pub(crate) fn flush(&mut self) -> Graphics {
+        let flushed = Self {
+            clear_color: self.clear_color.take(),
+            draw_commands: std::mem::take(&mut self.draw_commands),
+            context: self.context,
+            context_dirty: false,
+        };
+
+        // The next draw has to re-establish the context, since
+        // the commands which set it have been taken.
+        self.context_dirty = true;
+
+        flushed
+    }
+
     pub fn scoped<C>(&mut self, mut cb: C)
     where
         C: FnMut(&mut Self),
@@ -119,6 +202,15 @@ impl Graphics {
         self.context_mut().stroke_weight = weight;
     }
 
+    /// Draw strokes from now on with `mode`, such as
+    /// [`StrokeMode::Hairline`] for lines which stay one
+    /// pixel wide when zoomed in. The software renderer
+    /// doesn't anti-alias, so it draws smooth strokes as
+    /// standard ones.
+    pub fn stroke_mode(&mut self, mode: StrokeMode) {
+        self.context_mut().stroke_mode = mode;
+    }
+
     pub fn stroke<C>(&mut self, color: C)
     where
         C: Into<Color>,
@@ -174,43 +266,303 @@ impl Graphics {
         self.rect(position, Size::new(size, size));
     }
 
-    pub(crate) fn construct_buffer_data(self) -> Result<BufferData, tess::TessellationError> {
-        let mut current_context = self.context();
-
-        let mut builder = RawBuffersBuilder::default();
-
-        let mut fill_tess = tess::FillTessellator::new();
-        let mut stroke_tess = tess::StrokeTessellator::new();
-
-        for command in self.draw_commands.iter() {
-            match command {
-                DrawCommand::Draw(closed, points) => {
-                    let points = points.iter().map(|p| p.cast::<f32>());
-
-                    if current_context.fill.is_some() {
-                        fill_tess.tessellate(
-                            FromPolyline::new(*closed, points.clone()),
-                            &current_context.get_fill_options(),
-                            &mut builder,
-                        )?;
-                    }
-
-                    if current_context.stroke.is_some() {
-                        stroke_tess.tessellate(
-                            FromPolyline::new(*closed, points),
-                            &current_context.get_stroke_options(),
-                            &mut builder,
-                        )?;
-                    }
-                },
-                DrawCommand::UpdateContext(new_context) => {
-                    builder.set_context(*new_context);
-                    current_context = new_context;
-                },
-            }
+    /// Draw `image` stretched over the given rectangle.
+    pub fn image<P, S>(&mut self, image: &Image, position: P, size: S)
+    where
+        P: Into<Point>,
+        S: Into<Size>,
+    {
+        let uv = BoundingBox::new(Point::zero(), Point::new(1.0, 1.0));
+        self.image_uv(image, position, size, uv);
+    }
+
+    /// Draw the `uv` region of `image` over the given
+    /// rectangle. Texture coordinates outside of
+    /// `0.0..=1.0` are handled by the image's
+    /// [`WrapMode`](super::WrapMode), which is useful
+    /// for pattern fills.
+    pub fn image_uv<P, S>(&mut self, image: &Image, position: P, size: S, uv: BoundingBox)
+    where
+        P: Into<Point>,
+        S: Into<Size>,
+    {
+        self.update_context_if_dirty();
+
+        let position = position.into();
+        let size = size.into();
+        let bounds = BoundingBox {
+            min: position,
+            max: position + size.to_vector(),
+        };
+
+        let aligned = self.align_points(&[bounds.min, bounds.max], Some(bounds));
+        let rect = BoundingBox::new(aligned[0], aligned[1]);
+
+        let command = DrawCommand::Image(image.clone(), rect, uv);
+        self.draw_commands.push(command);
+    }
+
+    /// Draw `shape`, with `transform` applied before the
+    /// current transform.
+    ///
+    /// The GPU renderer keeps the shape's tessellated
+    /// geometry between frames, so this only costs a draw
+    /// call however complex the shape is.
+    pub fn shape(&mut self, shape: &Shape, transform: Transform) {
+        self.instanced(
+            shape,
+            &[Instance {
+                transform,
+                ..Default::default()
+            }],
+        );
+    }
+
+    /// Draw a copy of `shape` for every instance, each with
+    /// its own transform and tint. The GPU draws every copy
+    /// in a single call, so this is much faster than
+    /// drawing the shape repeatedly, such as for
+    /// particles.
+    ///
+    /// Each instance's transform is applied before the
+    /// current transform. The shape keeps its own fill and
+    /// stroke, multiplied by the instance's tint.
+    pub fn instanced(&mut self, shape: &Shape, instances: &[Instance]) {
+        self.update_context_if_dirty();
+
+        let command = DrawCommand::Instanced(shape.clone(), instances.into());
+        self.draw_commands.push(command);
+    }
+
+    /// Export the commands recorded so far as an SVG
+    /// document of the given size, keeping fills, strokes
+    /// and transforms as vectors. Anything flushed by
+    /// [`Sketch::load_pixels`] is no longer recorded, and
+    /// images drawn from canvases are left out. Images
+    /// tinted by an [`Instance`] keep the tint as a filter.
+    ///
+    /// [`Sketch::load_pixels`]: crate::sketch::Sketch::load_pixels
+    pub fn to_svg<S>(&self, size: S) -> String
+    where
+        S: Into<Size>,
+    {
+        svg::to_svg(
+            &shape::flatten(&self.draw_commands),
+            self.clear_color,
+            size.into(),
+        )
+    }
+
+    /// Write [`Graphics::to_svg`] to `path`.
+    pub fn save_svg<P, S>(&self, path: P, size: S) -> Result<(), SaveError>
+    where
+        P: AsRef<Path>,
+        S: Into<Size>,
+    {
+        std::fs::write(path, self.to_svg(size))?;
+        Ok(())
+    }
+
+    /// Write the commands recorded so far to `path` as a
+    /// single page PDF. Use [`PdfDocument`] for more pages.
+    pub fn save_pdf<P, S>(&self, path: P, size: S) -> Result<(), SaveError>
+    where
+        P: AsRef<Path>,
+        S: Into<Size>,
+    {
+        let mut document = PdfDocument::new();
+        document.add_page(self, size);
+        document.save(path)
+    }
+
+    /// Copy the commands recorded so far into a
+    /// [`DisplayList`], which can be saved and replayed
+    /// later.
+    #[cfg(feature = "serialize")]
+    pub fn to_display_list(&self) -> DisplayList {
+        DisplayList::new(&self.draw_commands, self.clear_color)
+    }
+
+    /// Append the commands of `list`, drawing them exactly
+    /// as they were recorded. The list's clear color, if it
+    /// has one, replaces this frame's. The current fill,
+    /// stroke and transform are left unchanged for whatever
+    /// is drawn afterwards.
+    #[cfg(feature = "serialize")]
+    pub fn replay(&mut self, list: &DisplayList) {
+        if let Some(color) = list.clear_color() {
+            self.clear_color = Some(color);
         }
 
-        let (vertices, indices) = builder.take();
-        Ok(BufferData { vertices, indices })
+        // Recorded commands start from the default context,
+        // rather than whatever is current.
+        self.draw_commands
+            .push(DrawCommand::UpdateContext(Context::default()));
+        self.draw_commands.extend(list.draw_commands());
+        self.context_dirty = true;
+    }
+
+    pub(super) fn commands(&self) -> &[DrawCommand] {
+        &self.draw_commands
+    }
+
+    pub(super) fn into_commands(self) -> Vec<DrawCommand> {
+        self.draw_commands
     }
+
+    /// Tessellate everything recorded so far into the
+    /// triangles which would be drawn, without rendering
+    /// them.
+    pub fn tessellate(&self) -> Result<Tessellation, tess::TessellationError> {
+        let mut data = self.construct_buffer_data(&mut TessellationCache::default(), false)?;
+        data.apply_transforms();
+        Ok(Tessellation::new(
+            &data.vertices,
+            data.indices,
+            data.commands,
+        ))
+    }
+
+    /// Build the vertex and index data for every recorded
+    /// command, reusing shapes tessellated in earlier
+    /// frames from `cache`.
+    ///
+    /// With `instancing`, each instanced draw is added once
+    /// along with its instances, for the GPU to draw in a
+    /// single call. Otherwise a copy of the shape is added
+    /// for every instance.
+    pub(crate) fn construct_buffer_data(
+        &self,
+        cache: &mut TessellationCache,
+        instancing: bool,
+    ) -> Result<BufferData, tess::TessellationError> {
+        let flattened;
+        let draw_commands = if instancing {
+            &self.draw_commands
+        }
+        else {
+            flattened = shape::flatten(&self.draw_commands);
+            &flattened
+        };
+
+        #[cfg(feature = "parallel")]
+        if let Some(data) = parallel::construct(draw_commands, cache, instancing) {
+            return data;
+        }
+
+        build_buffer_data(draw_commands, Context::default(), cache, instancing)
+    }
+}
+
+/// Build the buffer data of `draw_commands`, starting from
+/// `context`.
+pub(super) fn build_buffer_data(
+    draw_commands: &[DrawCommand],
+    context: Context,
+    cache: &mut TessellationCache,
+    instancing: bool,
+) -> Result<BufferData, tess::TessellationError> {
+    let mut builder = RawBuffersBuilder::default();
+    builder.set_context(context);
+    let mut commands = Vec::new();
+    // Everything which isn't instanced is drawn with this
+    // single untransformed and untinted instance.
+    let mut instances = vec![RawInstance::from(Instance::default())];
+
+    let mut current_context = context;
+    for command in draw_commands.iter() {
+        match command {
+            DrawCommand::Instanced(shape, shape_instances) if instancing => {
+                if shape_instances.is_empty() {
+                    continue;
+                }
+
+                let first = instances.len() as u32;
+                instances.extend(shape_instances.iter().map(|instance| {
+                    RawInstance::from(Instance {
+                        transform: instance.transform.then(&current_context.transform),
+                        tint: instance.tint,
+                    })
+                }));
+
+                // The shape's own geometry is tessellated once
+                // and kept by the renderer, so only its
+                // instances are added here.
+                builder.add_shape(shape, first..instances.len() as u32);
+            },
+            command => {
+                if let DrawCommand::UpdateContext(context) = command {
+                    current_context = *context;
+                }
+                add_commands(
+                    std::slice::from_ref(command),
+                    &mut builder,
+                    cache,
+                    &mut commands,
+                )?;
+            },
+        }
+    }
+
+    let (vertices, indices, batches, transforms) = builder.take();
+    Ok(BufferData {
+        vertices,
+        indices,
+        batches,
+        transforms,
+        instances,
+        commands,
+    })
+}
+
+/// Add the geometry of commands which aren't instanced,
+/// starting from the builder's current context.
+fn add_commands(
+    draw_commands: &[DrawCommand],
+    builder: &mut RawBuffersBuilder,
+    cache: &mut TessellationCache,
+    commands: &mut Vec<CommandGeometry>,
+) -> Result<(), tess::TessellationError> {
+    for command in draw_commands {
+        match command {
+            DrawCommand::Draw(closed, points) => {
+                builder.set_image(None);
+                let start = builder.index_count();
+
+                let context = *builder.context();
+                let (shape, origin) = cache.get(*closed, points, &context)?;
+
+                if let (Some(mesh), Some(color)) = (&shape.fill, context.fill) {
+                    builder.add_mesh(mesh, origin, color)?;
+                }
+                let middle = builder.index_count();
+
+                if let (Some(mesh), Some(color)) = (&shape.stroke, context.stroke) {
+                    builder.add_stroke_mesh(mesh, origin, color)?;
+                }
+
+                commands.push(CommandGeometry {
+                    kind: GeometryKind::Shape,
+                    fill: start..middle,
+                    stroke: middle..builder.index_count(),
+                });
+            },
+            DrawCommand::Image(image, rect, uv) => {
+                builder.set_image(Some(image));
+                let start = builder.index_count();
+                builder.add_image_quad(*rect, *uv)?;
+
+                let end = builder.index_count();
+                commands.push(CommandGeometry {
+                    kind: GeometryKind::Image,
+                    fill: start..end,
+                    stroke: end..end,
+                });
+            },
+            DrawCommand::UpdateContext(context) => builder.set_context(*context),
+            DrawCommand::Instanced(..) => unreachable!("instanced draws are flattened first"),
+        }
+    }
+
+    Ok(())
 }
@@ -0,0 +1,580 @@
+//! Images and the options used when sampling them
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rgb::RGBA8;
+use thiserror::Error;
+
+use super::pixels::{decode_color, encode_color, Pixels};
+use crate::types::{Color, Scalar, Size};
+
+pub(crate) type ImageId = u64;
+
+static NEXT_IMAGE_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("Expected {expected} pixels for the image size, but {actual} were provided")]
+    SizeMismatch { expected: usize, actual: usize },
+    #[error("Canvas images only exist on the GPU, and have no pixels to edit")]
+    RenderTarget,
+    #[error("Images must be at least 1x1, not {0}x{1}")]
+    Empty(u32, u32),
+}
+
+/// How texels are interpolated when an image is scaled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum FilterMode {
+    /// Nearest-neighbour sampling, for crisp pixel art.
+    Nearest,
+    /// Bilinear sampling, for photos and smooth scaling.
+    Linear,
+}
+
+/// What happens to texture coordinates outside of
+/// `0.0..=1.0`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum WrapMode {
+    Clamp,
+    Repeat,
+    Mirror,
+}
+
+/// Sampling options for drawing an image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sampling {
+    pub filter: FilterMode,
+    /// Generate and sample mipmaps when the image is drawn
+    /// smaller than its actual size.
+    pub mipmaps: bool,
+    pub wrap: WrapMode,
+}
+
+impl Sampling {
+    pub fn pixelated() -> Self {
+        Self {
+            filter: FilterMode::Nearest,
+            mipmaps: false,
+            wrap: WrapMode::Clamp,
+        }
+    }
+
+    pub fn smooth() -> Self {
+        Self {
+            filter: FilterMode::Linear,
+            mipmaps: true,
+            wrap: WrapMode::Clamp,
+        }
+    }
+
+    pub fn with_wrap(self, wrap: WrapMode) -> Self {
+        Self { wrap, ..self }
+    }
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            filter: FilterMode::Linear,
+            mipmaps: false,
+            wrap: WrapMode::Clamp,
+        }
+    }
+}
+
+/// Most edits remembered separately for each image. Older
+/// edits are merged together.
+const MAX_EDITS: usize = 16;
+
+/// Rectangle of pixels which have changed since the image
+/// was last uploaded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct DirtyRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl DirtyRegion {
+    fn union(self, other: Self) -> Self {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+pub(crate) struct ImageContents {
+    pub pixels: Vec<RGBA8>,
+    // Incremented by every edit.
+    version: u64,
+    // Recent edits, along with the version each one brought
+    // the image to. Each region covers every edit since the
+    // one before it.
+    edits: VecDeque<(u64, DirtyRegion)>,
+}
+
+impl ImageContents {
+    fn new(pixels: Vec<RGBA8>) -> Self {
+        Self {
+            pixels,
+            version: 0,
+            edits: VecDeque::new(),
+        }
+    }
+
+    fn mark_dirty(&mut self, region: DirtyRegion) {
+        self.version += 1;
+
+        // Merge the oldest edits rather than dropping them, so
+        // renderers which are far behind still see them.
+        if self.edits.len() == MAX_EDITS {
+            let (_, oldest) = self.edits.pop_front().unwrap();
+            let (_, next) = self.edits.front_mut().unwrap();
+            *next = next.union(oldest);
+        }
+        self.edits.push_back((self.version, region));
+    }
+
+    /// Version of the pixels, which changes with every
+    /// edit. Each renderer keeps the version it last
+    /// uploaded, so that an image drawn by several of them
+    /// is kept up to date in each.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// The region which changed after `version`, or `None`
+    /// when nothing has.
+    pub fn changed_since(&self, version: u64) -> Option<DirtyRegion> {
+        self.edits
+            .iter()
+            .filter(|(edit, _)| *edit > version)
+            .map(|(_, region)| *region)
+            .reduce(DirtyRegion::union)
+    }
+}
+
+pub(crate) struct ImageData {
+    pub id: ImageId,
+    pub width: u32,
+    pub height: u32,
+    // `None` for canvas images, which are rendered into on
+    // the GPU instead.
+    contents: Option<Mutex<ImageContents>>,
+}
+
+impl ImageData {
+    pub fn lock(&self) -> Option<MutexGuard<'_, ImageContents>> {
+        // Pixel data is always valid, even if a panic happened
+        // mid-edit, so poisoning can be ignored.
+        let contents = self.contents.as_ref()?;
+        Some(
+            contents
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        )
+    }
+
+    fn region(&self) -> DirtyRegion {
+        DirtyRegion {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+}
+
+/// An RGBA image which can be drawn with
+/// [`Graphics::image`](super::Graphics::image).
+///
+/// Images are cheap to clone, and clones share the same
+/// pixel data. Sampling options are kept per handle, so the
+/// same pixels can be drawn both pixelated and smooth.
+///
+/// Pixels can be edited at any time. Only the region which
+/// changed is uploaded to the GPU before the next frame is
+/// rendered, so images generated every frame stay cheap.
+/// Edits show up in every frame rendered after them, even
+/// if the image was drawn before the edit.
+#[derive(Clone)]
+pub struct Image {
+    data: Arc<ImageData>,
+    sampling: Option<Sampling>,
+}
+
+impl Image {
+    /// Create a fully transparent image. As with
+    /// [`Canvas`](super::Canvas), each side is at least one
+    /// pixel, since textures can't be empty.
+    pub fn new(width: u32, height: u32) -> Self {
+        let (width, height) = (width.max(1), height.max(1));
+        let pixels = vec![RGBA8::new(0, 0, 0, 0); width as usize * height as usize];
+        Self::from_rgba8(width, height, pixels).expect("pixel count matches the image size")
+    }
+
+    pub fn from_pixels(pixels: Pixels) -> Self {
+        let (width, height) = (pixels.width(), pixels.height());
+        Self::from_rgba8(width, height, pixels.into_raw())
+            .expect("pixel count matches the image size")
+    }
+
+    /// Create an image from sRGB encoded pixels, in
+    /// row-major order starting from the top left.
+    pub fn from_rgba8(width: u32, height: u32, pixels: Vec<RGBA8>) -> Result<Self, ImageError> {
+        if width == 0 || height == 0 {
+            return Err(ImageError::Empty(width, height));
+        }
+
+        let expected = width as usize * height as usize;
+        if pixels.len() != expected {
+            return Err(ImageError::SizeMismatch {
+                expected,
+                actual: pixels.len(),
+            });
+        }
+
+        let data = ImageData {
+            id: NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed),
+            width,
+            height,
+            contents: Some(Mutex::new(ImageContents::new(pixels))),
+        };
+
+        Ok(Self {
+            data: Arc::new(data),
+            sampling: None,
+        })
+    }
+
+    /// Image which is only rendered into by the GPU.
+    pub(crate) fn render_target(width: u32, height: u32) -> Self {
+        let data = ImageData {
+            id: NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed),
+            width,
+            height,
+            contents: None,
+        };
+
+        Self {
+            data: Arc::new(data),
+            sampling: None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.data.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.data.height
+    }
+
+    pub fn size(&self) -> Size {
+        Size::new(self.width() as Scalar, self.height() as Scalar)
+    }
+
+    /// Whether this image belongs to a
+    /// [`Canvas`](super::Canvas). Canvas images have no CPU
+    /// side pixels, so editing them does nothing, and
+    /// reading them has to go through
+    /// [`Sketch::load_canvas_pixels`](crate::sketch::Sketch::load_canvas_pixels).
+    pub fn is_render_target(&self) -> bool {
+        self.data.contents.is_none()
+    }
+
+    /// Copy of the image's current pixels, or `None` for
+    /// canvas images.
+    pub fn to_pixels(&self) -> Option<Pixels> {
+        let contents = self.data.lock()?;
+        Some(Pixels::from_raw(
+            self.width(),
+            self.height(),
+            contents.pixels.clone(),
+        ))
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.width() && y < self.height() {
+            Some((y * self.width() + x) as usize)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Color of the pixel at `(x, y)`, or `None` when out
+    /// of bounds.
+    pub fn get(&self, x: u32, y: u32) -> Option<Color> {
+        let i = self.index(x, y)?;
+        Some(decode_color(self.data.lock()?.pixels[i]))
+    }
+
+    /// Set the pixel at `(x, y)`. Out of bounds coordinates
+    /// are ignored.
+    pub fn set<C>(&mut self, x: u32, y: u32, color: C)
+    where
+        C: Into<Color>,
+    {
+        if let (Some(i), Some(mut contents)) = (self.index(x, y), self.data.lock()) {
+            contents.pixels[i] = encode_color(color.into());
+            contents.mark_dirty(DirtyRegion {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            });
+        }
+    }
+
+    /// Set every pixel to `color`.
+    pub fn fill<C>(&mut self, color: C)
+    where
+        C: Into<Color>,
+    {
+        let pixel = encode_color(color.into());
+
+        if let Some(mut contents) = self.data.lock() {
+            contents.pixels.iter_mut().for_each(|p| *p = pixel);
+            contents.mark_dirty(self.data.region());
+        }
+    }
+
+    /// Overwrite the image with `pixels`, which must be the
+    /// same size as the image.
+    pub fn update(&mut self, pixels: &Pixels) -> Result<(), ImageError> {
+        if pixels.width() != self.width() || pixels.height() != self.height() {
+            return Err(ImageError::SizeMismatch {
+                expected: self.width() as usize * self.height() as usize,
+                actual: pixels.as_raw().len(),
+            });
+        }
+
+        let mut contents = self.data.lock().ok_or(ImageError::RenderTarget)?;
+        contents.pixels.copy_from_slice(pixels.as_raw());
+        contents.mark_dirty(self.data.region());
+
+        Ok(())
+    }
+
+    /// Edit the raw sRGB encoded pixels of the whole image
+    /// in place. Prefer [`set`](Image::set) for sparse
+    /// edits, so that less data has to be uploaded.
+    pub fn edit<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut [RGBA8]),
+    {
+        if let Some(mut contents) = self.data.lock() {
+            f(&mut contents.pixels);
+            contents.mark_dirty(self.data.region());
+        }
+    }
+
+    /// Sampling options for this handle. When `None`, the
+    /// default from [`Settings`](crate::sketch::Settings)
+    /// is used.
+    pub fn sampling(&self) -> Option<Sampling> {
+        self.sampling
+    }
+
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = Some(sampling);
+    }
+
+    pub fn with_sampling(mut self, sampling: Sampling) -> Self {
+        self.set_sampling(sampling);
+        self
+    }
+
+    pub(crate) fn id(&self) -> ImageId {
+        self.data.id
+    }
+
+    pub(crate) fn data(&self) -> &Arc<ImageData> {
+        &self.data
+    }
+}
+
+/// Width, height and pixels of one level of a mip chain.
+pub(crate) type MipLevel = (u32, u32, Vec<RGBA8>);
+
+/// Build every mip level below the full sized image by
+/// averaging 2x2 blocks of the previous level.
+pub(crate) fn generate_mips(width: u32, height: u32, pixels: &[RGBA8]) -> Vec<MipLevel> {
+    let mut levels = Vec::new();
+
+    let (mut level_width, mut level_height) = (width, height);
+    while level_width > 1 || level_height > 1 {
+        level_width = (level_width / 2).max(1);
+        level_height = (level_height / 2).max(1);
+
+        let len = (level_width * level_height) as usize;
+        levels.push((level_width, level_height, vec![RGBA8::default(); len]));
+    }
+
+    let region = DirtyRegion {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    };
+    update_mips(width, height, pixels, &mut levels, region);
+
+    levels
+}
+
+/// Rebuild the pixels of `levels` which are averaged from
+/// `region` of the full sized image, returning the region
+/// which changed in each level.
+pub(crate) fn update_mips(
+    width: u32,
+    height: u32,
+    pixels: &[RGBA8],
+    levels: &mut [MipLevel],
+    region: DirtyRegion,
+) -> Vec<DirtyRegion> {
+    let mut regions = Vec::with_capacity(levels.len());
+    let (mut width, mut height, mut region) = (width, height, region);
+
+    for i in 0..levels.len() {
+        let (built, rest) = levels.split_at_mut(i);
+        let previous = match built.last() {
+            Some((_, _, pixels)) => pixels.as_slice(),
+            None => pixels,
+        };
+        let (next_width, next_height, next) = &mut rest[0];
+
+        // Every pixel whose 2x2 block overlaps the region.
+        let right = (region.x + region.width).div_ceil(2).min(*next_width);
+        let bottom = (region.y + region.height).div_ceil(2).min(*next_height);
+        let left = (region.x / 2).min(right);
+        let top = (region.y / 2).min(bottom);
+
+        for y in top..bottom {
+            for x in left..right {
+                let mut sum = [0u32; 4];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (x * 2 + dx).min(width - 1);
+                    let sy = (y * 2 + dy).min(height - 1);
+                    let p = previous[(sy * width + sx) as usize];
+
+                    sum[0] += p.r as u32;
+                    sum[1] += p.g as u32;
+                    sum[2] += p.b as u32;
+                    sum[3] += p.a as u32;
+                }
+
+                next[(y * *next_width + x) as usize] = RGBA8::new(
+                    (sum[0] / 4) as u8,
+                    (sum[1] / 4) as u8,
+                    (sum[2] / 4) as u8,
+                    (sum[3] / 4) as u8,
+                );
+            }
+        }
+
+        region = DirtyRegion {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        };
+        regions.push(region);
+
+        width = *next_width;
+        height = *next_height;
+    }
+
+    regions
+}
+
+pub(crate) fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: u32, y: u32, width: u32, height: u32) -> DirtyRegion {
+        DirtyRegion {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn updated_mips_match_generated() {
+        let (width, height) = (13, 7);
+        let mut pixels: Vec<RGBA8> = (0..width * height)
+            .map(|i| RGBA8::new(i as u8, (i * 7) as u8, (i * 13) as u8, 255))
+            .collect();
+        let mut levels = generate_mips(width, height, &pixels);
+
+        for (x, y) in [(0, 0), (12, 6), (5, 3), (12, 0)] {
+            pixels[(y * width + x) as usize] = RGBA8::new(255, 0, 255, 0);
+            let regions = update_mips(width, height, &pixels, &mut levels, region(x, y, 1, 1));
+
+            assert_eq!(levels, generate_mips(width, height, &pixels));
+            assert!(regions.iter().all(|r| r.width <= 1 && r.height <= 1));
+        }
+    }
+
+    #[test]
+    fn edits_are_tracked_per_version() {
+        let mut contents = ImageContents::new(vec![RGBA8::default(); 64]);
+        assert_eq!(contents.changed_since(0), None);
+
+        contents.mark_dirty(region(1, 1, 1, 1));
+        let first = contents.version();
+        contents.mark_dirty(region(4, 4, 2, 2));
+
+        assert_eq!(contents.changed_since(0), Some(region(1, 1, 5, 5)));
+        assert_eq!(contents.changed_since(first), Some(region(4, 4, 2, 2)));
+        assert_eq!(contents.changed_since(contents.version()), None);
+    }
+
+    #[test]
+    fn old_edits_are_merged() {
+        let mut contents = ImageContents::new(vec![RGBA8::default(); 64 * 64]);
+        for i in 0..MAX_EDITS as u32 * 2 {
+            contents.mark_dirty(region(i, i, 1, 1));
+        }
+
+        assert_eq!(contents.edits.len(), MAX_EDITS);
+        let all = MAX_EDITS as u32 * 2;
+        assert_eq!(contents.changed_since(0), Some(region(0, 0, all, all)));
+        assert_eq!(
+            contents.changed_since(contents.version() - 1),
+            Some(region(all - 1, all - 1, 1, 1))
+        );
+    }
+
+    #[test]
+    fn images_are_never_empty() {
+        let image = Image::new(0, 3);
+        assert_eq!((image.width(), image.height()), (1, 3));
+        assert_eq!(image.to_pixels().unwrap().as_raw().len(), 3);
+
+        assert!(matches!(
+            Image::from_rgba8(4, 0, Vec::new()),
+            Err(ImageError::Empty(4, 0))
+        ));
+    }
+}
@@ -1,8 +1,40 @@
+mod backend;
+mod buffer;
+mod cache;
+mod canvas;
 mod construct;
 mod context;
+#[cfg(feature = "serialize")]
+mod display_list;
 mod graphics;
+mod image;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod pdf;
+mod pixels;
+mod record;
 mod renderer;
+mod save;
+mod shape;
+mod software;
+mod svg;
+mod target;
+mod tessellation;
 
-pub use context::AnchorMode;
+pub use backend::Backend;
+pub(crate) use backend::RenderBackend;
+pub use cache::TessellationStats;
+pub use canvas::Canvas;
+pub use context::{AnchorMode, StrokeMode};
+#[cfg(feature = "serialize")]
+pub use display_list::{DisplayList, DisplayListError};
 pub use graphics::Graphics;
-pub(crate) use renderer::Renderer;
+pub use image::{FilterMode, Image, ImageError, Sampling, WrapMode};
+pub use pdf::PdfDocument;
+pub use pixels::Pixels;
+pub(crate) use record::Recorder;
+pub use record::{Recording, RecordingFormat, Video};
+pub use renderer::{PresentMode, RenderError, RendererInitError};
+pub use save::{frame_path, LoadError, SaveError};
+pub use shape::{Instance, Shape, ShapeStyle};
+pub use tessellation::{CommandGeometry, GeometryKind, Tessellation, Vertex};
@@ -0,0 +1,305 @@
+//! Tessellating long command lists across threads
+
+use std::num::NonZeroUsize;
+use std::ops::Range;
+use std::thread;
+
+use super::cache::TessellationCache;
+use super::context::Context;
+use super::graphics::{build_buffer_data, BufferData, DrawCommand};
+use crate::tess::TessellationError;
+use crate::types::Index;
+
+/// Fewest commands worth handing to another thread.
+const MIN_CHUNK: usize = 512;
+
+/// Build the buffer data of `draw_commands` across several
+/// threads, or return `None` when there are too few
+/// commands for it to be worthwhile.
+///
+/// The commands are split into contiguous runs of roughly
+/// equal work, each tessellated from the context in effect
+/// where it begins. The results are then joined in order,
+/// so the output matches building on a single thread.
+///
+/// Threads are spawned for every build rather than kept in
+/// a pool, which costs far less than tessellating even a
+/// single chunk. Each thread has its own cache, picked by
+/// the position of its run. Runs are split by work, so when
+/// the commands before a shape change, it may move to
+/// another thread and be tessellated again there.
+pub(super) fn construct(
+    draw_commands: &[DrawCommand],
+    cache: &mut TessellationCache,
+    instancing: bool,
+) -> Option<Result<BufferData, TessellationError>> {
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let chunks = threads.min(draw_commands.len() / MIN_CHUNK);
+    if chunks < 2 {
+        return None;
+    }
+
+    Some(construct_in(draw_commands, cache, instancing, chunks))
+}
+
+/// Build the buffer data of `draw_commands` on `chunks`
+/// threads.
+fn construct_in(
+    draw_commands: &[DrawCommand],
+    cache: &mut TessellationCache,
+    instancing: bool,
+    chunks: usize,
+) -> Result<BufferData, TessellationError> {
+    let parts = partition(draw_commands, chunks);
+    let workers = cache.workers(parts.len());
+
+    let results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = parts
+            .into_iter()
+            .zip(workers.iter_mut())
+            .map(|((range, context), cache)| {
+                let commands = &draw_commands[range];
+                scope.spawn(move || build_buffer_data(commands, context, cache, instancing))
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            })
+            .collect()
+    });
+
+    merge(results)
+}
+
+/// Rough cost of tessellating a command.
+fn weight(command: &DrawCommand) -> usize {
+    match command {
+        DrawCommand::Draw(_, points) => points.len(),
+        DrawCommand::Image(..) => 4,
+        DrawCommand::UpdateContext(_) => 0,
+        DrawCommand::Instanced(_, instances) => instances.len(),
+    }
+}
+
+/// Split `draw_commands` into `chunks` runs, along with the
+/// context each run starts from.
+fn partition(draw_commands: &[DrawCommand], chunks: usize) -> Vec<(Range<usize>, Context)> {
+    let total: usize = draw_commands.iter().map(weight).sum();
+    let target = total / chunks + 1;
+
+    let mut parts = Vec::with_capacity(chunks);
+    let mut start = 0;
+    let mut start_context = Context::default();
+    let mut context = start_context;
+    let mut accumulated = 0;
+
+    for (i, command) in draw_commands.iter().enumerate() {
+        if let DrawCommand::UpdateContext(new_context) = command {
+            context = *new_context;
+        }
+
+        accumulated += weight(command);
+        if accumulated >= target && parts.len() + 1 < chunks {
+            parts.push((start..i + 1, start_context));
+            start = i + 1;
+            start_context = context;
+            accumulated = 0;
+        }
+    }
+    parts.push((start..draw_commands.len(), start_context));
+
+    parts
+}
+
+fn merge(
+    results: Vec<Result<BufferData, TessellationError>>,
+) -> Result<BufferData, TessellationError> {
+    let mut results = results.into_iter();
+    let mut data = results.next().expect("there are always several parts")?;
+
+    for part in results {
+        append(&mut data, part?)?;
+    }
+
+    Ok(data)
+}
+
+/// Append `other` to `data`, offsetting everything which
+/// refers into its buffers.
+fn append(data: &mut BufferData, other: BufferData) -> Result<(), TessellationError> {
+    if data.vertices.len() + other.vertices.len() > Index::MAX as usize {
+        return Err(TessellationError::TooManyVertices);
+    }
+
+    let vertex_offset = data.vertices.len() as Index;
+    let index_offset = data.indices.len() as Index;
+    // A part which starts on the transform the last one
+    // ended with keeps using it, as a single thread would.
+    let shared = match (data.transforms.last(), other.transforms.first()) {
+        (Some(last), Some(first)) => last == first,
+        _ => false,
+    };
+    let transform_offset = data.transforms.len() as u32 - shared as u32;
+    // Every part starts with the same default instance,
+    // which is only kept once.
+    let instance_offset = data.instances.len() as u32 - 1;
+
+    data.vertices
+        .extend(other.vertices.into_iter().map(|mut vertex| {
+            vertex.transform += transform_offset;
+            vertex
+        }));
+    data.indices
+        .extend(other.indices.iter().map(|index| index + vertex_offset));
+    data.transforms
+        .extend(other.transforms.into_iter().skip(shared as usize));
+    data.instances.extend(other.instances.into_iter().skip(1));
+
+    for mut batch in other.batches {
+        batch.indices = batch.indices.start + index_offset..batch.indices.end + index_offset;
+        if batch.instances != (0..1) {
+            batch.instances =
+                batch.instances.start + instance_offset..batch.instances.end + instance_offset;
+        }
+
+        match data.batches.last_mut() {
+            Some(last) if last.continues_with(&batch) => last.indices.end = batch.indices.end,
+            _ => data.batches.push(batch),
+        }
+    }
+
+    let offset = index_offset as usize;
+    data.commands
+        .extend(other.commands.into_iter().map(|mut command| {
+            command.fill = command.fill.start + offset..command.fill.end + offset;
+            command.stroke = command.stroke.start + offset..command.stroke.end + offset;
+            command
+        }));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::image::Image;
+    use crate::render::shape::{self, Instance, Shape};
+    use crate::render::{Graphics, Tessellation};
+    use crate::types::{Angle, Color, Scalar};
+
+    /// Enough commands to be split into several parts, with
+    /// context changes, images and instanced draws spread
+    /// through them.
+    fn graphics() -> Graphics {
+        let image = Image::new(4, 4);
+        let dot = Shape::new(|gfx| {
+            gfx.fill(Color::new(1.0, 1.0, 1.0, 1.0));
+            gfx.square([0.0, 0.0], 4.0);
+        });
+        let instances: Vec<Instance> = (0..8)
+            .map(|i| Instance::at([i as Scalar * 5.0, 0.0]))
+            .collect();
+
+        let mut gfx = Graphics::default();
+        for i in 0..MIN_CHUNK * 3 {
+            let x = (i % 50) as Scalar * 10.0;
+            let y = (i / 50) as Scalar * 10.0;
+
+            match i % 4 {
+                0 => gfx.fill(Color::new(x / 500.0, y / 300.0, 0.5, 1.0)),
+                1 => gfx.stroke_weight((i % 5) as f32 + 1.0),
+                _ => {},
+            }
+            if i % 7 == 0 {
+                gfx.rotate(Angle::degrees(1.0));
+            }
+
+            match i % 3 {
+                0 => gfx.rect([x, y], [8.0, 6.0]),
+                1 => gfx.image(&image, [x, y], [8.0, 8.0]),
+                _ => gfx.instanced(&dot, &instances),
+            }
+        }
+
+        gfx
+    }
+
+    /// Everything in `data` which ends up on the GPU.
+    fn summary(data: &BufferData) -> String {
+        let batches: Vec<_> = data
+            .batches
+            .iter()
+            .map(|batch| {
+                (
+                    batch.image.as_ref().map(Image::id),
+                    batch.indices.clone(),
+                    batch.instances.clone(),
+                    batch.shape.is_some(),
+                )
+            })
+            .collect();
+
+        format!(
+            "{:?} {:?} {:?} {:?} {:?} {:?}",
+            data.vertices, data.indices, data.transforms, data.instances, data.commands, batches
+        )
+    }
+
+    #[test]
+    fn parts_match_single_thread() {
+        let gfx = graphics();
+
+        for instancing in [false, true] {
+            let commands = if instancing {
+                gfx.commands().to_vec()
+            }
+            else {
+                shape::flatten(gfx.commands())
+            };
+            assert!(commands.len() > MIN_CHUNK * 2);
+
+            let single = build_buffer_data(
+                &commands,
+                Context::default(),
+                &mut TessellationCache::default(),
+                instancing,
+            )
+            .unwrap();
+
+            for chunks in 2..=4 {
+                let parallel = construct_in(
+                    &commands,
+                    &mut TessellationCache::default(),
+                    instancing,
+                    chunks,
+                )
+                .unwrap();
+
+                assert_eq!(summary(&parallel), summary(&single));
+            }
+        }
+    }
+
+    #[test]
+    fn tessellate_matches_single_thread() {
+        let gfx = graphics();
+
+        let commands = shape::flatten(gfx.commands());
+        let mut single = build_buffer_data(
+            &commands,
+            Context::default(),
+            &mut TessellationCache::default(),
+            false,
+        )
+        .unwrap();
+        single.apply_transforms();
+        let single = Tessellation::new(&single.vertices, single.indices, single.commands);
+
+        assert_eq!(gfx.tessellate().unwrap(), single);
+    }
+}
@@ -0,0 +1,511 @@
+//! Exporting recorded draw commands as PDF
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use miniz_oxide::deflate::{compress_to_vec_zlib, CompressionLevel};
+use pdf_writer::{Content, Filter, Finish, Name, Pdf, Rect, Ref};
+use rgb::RGBA8;
+
+use super::context::{Context, StrokeMode};
+use super::graphics::{DrawCommand, Graphics};
+use super::image::{FilterMode, Image, ImageId};
+use super::pixels::{encode_color, Pixels};
+use super::save::SaveError;
+use super::shape;
+use crate::types::{Color, Size};
+
+struct Page {
+    size: Size,
+    clear_color: Option<Color>,
+    commands: Vec<DrawCommand>,
+}
+
+/// A PDF built from recorded [`Graphics`], one page per
+/// frame. Fills and strokes are kept as vectors, and images
+/// are embedded at their full resolution, with any tint
+/// from an [`Instance`](super::Instance). One pixel maps to
+/// one point on the page.
+#[derive(Default)]
+pub struct PdfDocument {
+    pages: Vec<Page>,
+    // Commands flushed part way through the page being
+    // recorded.
+    partial: Option<Page>,
+}
+
+impl PdfDocument {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a page of the given size with everything
+    /// recorded in `gfx`.
+    pub fn add_page<S>(&mut self, gfx: &Graphics, size: S)
+    where
+        S: Into<Size>,
+    {
+        let mut commands = shape::flatten(gfx.commands());
+        let page = match self.partial.take() {
+            Some(mut page) => {
+                page.commands.append(&mut commands);
+                page.size = size.into();
+                page.clear_color = page.clear_color.or(gfx.clear_color);
+                page
+            },
+            None => {
+                Page {
+                    size: size.into(),
+                    clear_color: gfx.clear_color,
+                    commands,
+                }
+            },
+        };
+
+        self.pages.push(page);
+    }
+
+    /// Add everything recorded in `gfx` to the start of the
+    /// next page, for commands which are flushed before the
+    /// rest of the frame is drawn.
+    pub fn extend_page(&mut self, gfx: &Graphics) {
+        let mut commands = shape::flatten(gfx.commands());
+        match &mut self.partial {
+            Some(page) => page.commands.append(&mut commands),
+            None => {
+                self.partial = Some(Page {
+                    size: Size::zero(),
+                    clear_color: gfx.clear_color,
+                    commands,
+                });
+            },
+        }
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut resources = Resources::default();
+        let contents: Vec<Vec<u8>> = self
+            .pages
+            .iter()
+            .map(|page| page_content(page, &mut resources))
+            .collect();
+
+        let mut pdf = Pdf::new();
+        let mut alloc = Ref::new(1);
+        let catalog_id = alloc.bump();
+        let tree_id = alloc.bump();
+
+        let level = CompressionLevel::DefaultLevel as u8;
+
+        let mut image_ids = Vec::with_capacity(resources.images.len());
+        for (pixels, interpolate, tint) in &resources.images {
+            let id = alloc.bump();
+            let mask_id = alloc.bump();
+            image_ids.push(id);
+
+            let raw = pixels.as_raw();
+            let color: Vec<u8> = raw.iter().flat_map(|p| [p.r, p.g, p.b]).collect();
+            let alpha: Vec<u8> = raw.iter().map(|p| p.a).collect();
+
+            let color = compress_to_vec_zlib(&color, level);
+            let mut image = pdf.image_xobject(id, &color);
+            image.filter(Filter::FlateDecode);
+            image.width(pixels.width() as i32);
+            image.height(pixels.height() as i32);
+            image.color_space().device_rgb();
+            image.bits_per_component(8);
+            image.interpolate(*interpolate);
+            image.s_mask(mask_id);
+            if (tint.r, tint.g, tint.b) != (255, 255, 255) {
+                let [r, g, b] = [tint.r, tint.g, tint.b].map(|c| c as f32 / 255.0);
+                image.decode([0.0, r, 0.0, g, 0.0, b]);
+            }
+            image.finish();
+
+            let alpha = compress_to_vec_zlib(&alpha, level);
+            let mut mask = pdf.image_xobject(mask_id, &alpha);
+            mask.filter(Filter::FlateDecode);
+            mask.width(pixels.width() as i32);
+            mask.height(pixels.height() as i32);
+            mask.color_space().device_gray();
+            mask.bits_per_component(8);
+            mask.interpolate(*interpolate);
+            if tint.a < 255 {
+                mask.decode([0.0, tint.a as f32 / 255.0]);
+            }
+            mask.finish();
+        }
+
+        let mut state_ids = Vec::with_capacity(resources.alphas.len());
+        for (fill, stroke) in &resources.alphas {
+            let id = alloc.bump();
+            state_ids.push(id);
+
+            pdf.ext_graphics(id)
+                .non_stroking_alpha(*fill as f32 / 255.0)
+                .stroking_alpha(*stroke as f32 / 255.0);
+        }
+
+        let page_ids: Vec<(Ref, Ref)> = self
+            .pages
+            .iter()
+            .map(|_| (alloc.bump(), alloc.bump()))
+            .collect();
+
+        pdf.catalog(catalog_id).pages(tree_id);
+
+        // Resources are inherited from the page tree, so they
+        // only have to be written once.
+        let image_names: Vec<String> = (0..image_ids.len()).map(image_name).collect();
+        let state_names: Vec<String> = (0..state_ids.len()).map(state_name).collect();
+
+        let mut tree = pdf.pages(tree_id);
+        tree.kids(page_ids.iter().map(|(page_id, _)| *page_id))
+            .count(page_ids.len() as i32);
+
+        let mut page_resources = tree.resources();
+        let mut x_objects = page_resources.x_objects();
+        for (name, id) in image_names.iter().zip(&image_ids) {
+            x_objects.pair(Name(name.as_bytes()), *id);
+        }
+        x_objects.finish();
+
+        let mut states = page_resources.ext_g_states();
+        for (name, id) in state_names.iter().zip(&state_ids) {
+            states.pair(Name(name.as_bytes()), *id);
+        }
+        states.finish();
+        page_resources.finish();
+        tree.finish();
+
+        for ((page, content), (page_id, content_id)) in
+            self.pages.iter().zip(&contents).zip(&page_ids)
+        {
+            let size = page.size.cast::<f32>();
+            let mut writer = pdf.page(*page_id);
+            writer.media_box(Rect::new(0.0, 0.0, size.width, size.height));
+            writer.parent(tree_id);
+            writer.contents(*content_id);
+            writer.finish();
+
+            pdf.stream(*content_id, &compress_to_vec_zlib(content, level))
+                .filter(Filter::FlateDecode);
+        }
+
+        pdf.finish()
+    }
+
+    pub fn save<P>(&self, path: P) -> Result<(), SaveError>
+    where
+        P: AsRef<Path>,
+    {
+        std::fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+}
+
+fn image_name(index: usize) -> String {
+    format!("Im{}", index)
+}
+
+fn state_name(index: usize) -> String {
+    format!("Gs{}", index)
+}
+
+/// Images and graphics states used across every page.
+/// Each tint of an image is its own XObject, which scales
+/// the image's samples with a decode array.
+#[derive(Default)]
+struct Resources {
+    images: Vec<(Pixels, bool, RGBA8)>,
+    image_indices: HashMap<(ImageId, RGBA8), usize>,
+    alphas: Vec<(u8, u8)>,
+    alpha_indices: HashMap<(u8, u8), usize>,
+}
+
+impl Resources {
+    fn image(&mut self, image: &Image, tint: Color) -> Option<usize> {
+        // Samples are sRGB encoded, and scaling them by the
+        // encoded tint is close to scaling the linear colors.
+        let tint = encode_color(tint);
+        if let Some(index) = self.image_indices.get(&(image.id(), tint)) {
+            return Some(*index);
+        }
+
+        // Canvases only exist on the GPU, so there is nothing
+        // to embed.
+        let pixels = image.to_pixels()?;
+        let interpolate = !matches!(
+            image.sampling().map(|sampling| sampling.filter),
+            Some(FilterMode::Nearest)
+        );
+
+        let index = self.images.len();
+        self.images.push((pixels, interpolate, tint));
+        self.image_indices.insert((image.id(), tint), index);
+        Some(index)
+    }
+
+    fn alpha(&mut self, fill: u8, stroke: u8) -> usize {
+        let alphas = &mut self.alphas;
+        *self.alpha_indices.entry((fill, stroke)).or_insert_with(|| {
+            alphas.push((fill, stroke));
+            alphas.len() - 1
+        })
+    }
+}
+
+fn page_content(page: &Page, resources: &mut Resources) -> Vec<u8> {
+    let mut content = Content::new();
+    let size = page.size.cast::<f32>();
+
+    // PDF's origin is at the bottom left, so flip the page to
+    // match.
+    content.transform([1.0, 0.0, 0.0, -1.0, 0.0, size.height]);
+
+    if let Some(color) = page.clear_color {
+        let [r, g, b] = rgb(color);
+        content.set_fill_rgb(r, g, b);
+        content.rect(0.0, 0.0, size.width, size.height);
+        content.fill_nonzero();
+    }
+
+    let mut context = Context::default();
+    content.save_state();
+    apply_context(&mut content, &context, resources);
+
+    for command in &page.commands {
+        match command {
+            DrawCommand::Draw(closed, points) => {
+                let (first, rest) = match points.split_first() {
+                    Some(split) => split,
+                    None => continue,
+                };
+
+                let first = first.cast::<f32>();
+                content.move_to(first.x, first.y);
+                for point in rest {
+                    let point = point.cast::<f32>();
+                    content.line_to(point.x, point.y);
+                }
+                if *closed {
+                    content.close_path();
+                }
+
+                // lyon fills with the even-odd rule by default.
+                match (context.fill.is_some(), context.stroke.is_some()) {
+                    (true, true) => content.fill_even_odd_and_stroke(),
+                    (true, false) => content.fill_even_odd(),
+                    (false, true) => content.stroke(),
+                    (false, false) => content.end_path(),
+                };
+            },
+            DrawCommand::Image(image, rect, uv) => {
+                // Flipped coordinates mirror the image, which
+                // the signed sizes below take care of, but
+                // there's nothing to scale up from no area.
+                if uv.width() == 0.0 || uv.height() == 0.0 {
+                    continue;
+                }
+                let index = match resources.image(image, context.tint) {
+                    Some(index) => index,
+                    None => continue,
+                };
+
+                // Scale the whole image so `uv` covers `rect`,
+                // and clip away the rest.
+                let rect = rect.cast::<f32>();
+                let uv = uv.cast::<f32>();
+                let width = rect.width() / uv.width();
+                let height = rect.height() / uv.height();
+                let x = rect.min.x - uv.min.x * width;
+                let y = rect.min.y - uv.min.y * height;
+
+                content.save_state();
+                content.rect(rect.min.x, rect.min.y, rect.width(), rect.height());
+                content.clip_nonzero();
+                content.end_path();
+
+                // The fill's opacity would otherwise fade the
+                // image too.
+                if alpha(context.fill) < 255 {
+                    let name = state_name(resources.alpha(255, alpha(context.stroke)));
+                    content.set_parameters(Name(name.as_bytes()));
+                }
+
+                // Images are drawn into a unit square with the
+                // first row at the top.
+                content.transform([width, 0.0, 0.0, -height, x, y + height]);
+                let name = image_name(index);
+                content.x_object(Name(name.as_bytes()));
+                content.restore_state();
+            },
+            DrawCommand::UpdateContext(new_context) => {
+                context = *new_context;
+
+                content.restore_state();
+                content.save_state();
+                apply_context(&mut content, &context, resources);
+            },
+            DrawCommand::Instanced(..) => unreachable!("instanced draws are flattened first"),
+        }
+    }
+
+    content.restore_state();
+    content.finish()
+}
+
+fn apply_context(content: &mut Content, context: &Context, resources: &mut Resources) {
+    let t = context.transform.cast::<f32>();
+    content.transform([t.m11, t.m12, t.m21, t.m22, t.m31, t.m32]);
+
+    // Match lyon's default stroke options. A width of zero
+    // is the thinnest line the viewer can show.
+    content.set_line_width(match context.stroke_mode {
+        StrokeMode::Hairline => 0.0,
+        _ => context.stroke_weight,
+    });
+    content.set_miter_limit(4.0);
+
+    if let Some(color) = context.fill {
+        let [r, g, b] = rgb(color);
+        content.set_fill_rgb(r, g, b);
+    }
+    if let Some(color) = context.stroke {
+        let [r, g, b] = rgb(color);
+        content.set_stroke_rgb(r, g, b);
+    }
+
+    let fill = alpha(context.fill);
+    let stroke = alpha(context.stroke);
+    if fill < 255 || stroke < 255 {
+        let name = state_name(resources.alpha(fill, stroke));
+        content.set_parameters(Name(name.as_bytes()));
+    }
+}
+
+/// sRGB encoded components, as PDF's `DeviceRGB` expects.
+fn rgb(color: Color) -> [f32; 3] {
+    let pixel = encode_color(color);
+    [
+        pixel.r as f32 / 255.0,
+        pixel.g as f32 / 255.0,
+        pixel.b as f32 / 255.0,
+    ]
+}
+
+fn alpha(color: Option<Color>) -> u8 {
+    color.map_or(255, |color| encode_color(color).a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{AnchorMode, Instance, Shape};
+    use crate::types::BoundingBox;
+
+    /// The content stream of each page, uncompressed.
+    fn contents(document: &PdfDocument) -> Vec<String> {
+        let mut resources = Resources::default();
+        document
+            .pages
+            .iter()
+            .map(|page| String::from_utf8(page_content(page, &mut resources)).unwrap())
+            .collect()
+    }
+
+    fn graphics() -> Graphics {
+        let mut gfx = Graphics::default();
+        gfx.anchor_mode(AnchorMode::First);
+        gfx
+    }
+
+    #[test]
+    fn shapes_are_paths() {
+        let mut gfx = graphics();
+        gfx.fill(Color::new(1.0, 0.0, 0.0, 1.0));
+        gfx.stroke(Color::new(0.0, 0.0, 1.0, 1.0));
+        gfx.stroke_weight(3.0);
+        gfx.translate([10.0, 20.0]);
+        gfx.rect([0.0, 0.0], [4.0, 2.0]);
+
+        let mut document = PdfDocument::new();
+        document.add_page(&gfx, [40.0, 30.0]);
+        let content = &contents(&document)[0];
+
+        // The page is flipped, then the context applied.
+        assert!(content.starts_with("1 0 0 -1 0 30 cm\n"), "{}", content);
+        assert!(content.contains("1 0 0 1 10 20 cm\n3 w\n"), "{}", content);
+        assert!(content.contains("1 0 0 rg\n0 0 1 RG\n"), "{}", content);
+        assert!(
+            content.contains("0 0 m\n4 0 l\n4 2 l\n0 2 l\nh\nB*\n"),
+            "{}",
+            content
+        );
+
+        gfx.no_fill();
+        gfx.rect([0.0, 0.0], [4.0, 2.0]);
+        document.add_page(&gfx, [40.0, 30.0]);
+        assert!(contents(&document)[1].ends_with("h\nS\nQ"));
+    }
+
+    #[test]
+    fn flushed_commands_start_the_page() {
+        let mut flushed = graphics();
+        flushed.rect([1.0, 1.0], [1.0, 1.0]);
+        let mut rest = graphics();
+        rest.rect([5.0, 5.0], [1.0, 1.0]);
+
+        let mut document = PdfDocument::new();
+        document.extend_page(&flushed);
+        document.add_page(&rest, [10.0, 10.0]);
+        document.add_page(&rest, [10.0, 10.0]);
+
+        assert_eq!(document.page_count(), 2);
+        let contents = contents(&document);
+        let first = contents[0].find("1 1 m").unwrap();
+        let second = contents[0].find("5 5 m").unwrap();
+        assert!(first < second);
+        assert!(!contents[1].contains("1 1 m"));
+    }
+
+    #[test]
+    fn mirrored_images_are_drawn() {
+        let image = Image::new(2, 2);
+        let mut gfx = graphics();
+        let flipped = BoundingBox::new([1.0, 0.0].into(), [0.0, 1.0].into());
+        gfx.image_uv(&image, [0.0, 0.0], [4.0, 4.0], flipped);
+
+        let mut document = PdfDocument::new();
+        document.add_page(&gfx, [10.0, 10.0]);
+        let content = &contents(&document)[0];
+
+        // A negative width mirrors the image across the clip.
+        assert!(content.contains("-4 0 0 -4 4 4 cm\n/Im0 Do"), "{}", content);
+    }
+
+    #[test]
+    fn tinted_images_are_decoded() {
+        let image = Image::new(2, 2);
+        let shape = Shape::new(|gfx| gfx.image(&image, [0.0, 0.0], [2.0, 2.0]));
+        let tint = Color::new(1.0, 0.0, 0.0, 0.5);
+
+        let mut gfx = graphics();
+        gfx.image(&image, [0.0, 0.0], [2.0, 2.0]);
+        gfx.instanced(&shape, &[Instance::at([4.0, 4.0]).with_tint(tint)]);
+
+        let mut document = PdfDocument::new();
+        document.add_page(&gfx, [10.0, 10.0]);
+        let content = &contents(&document)[0];
+        assert!(content.contains("/Im0 Do") && content.contains("/Im1 Do"));
+
+        let bytes = document.to_bytes();
+        let pdf = String::from_utf8_lossy(&bytes);
+        assert!(pdf.contains("/Decode [0 1 0 0 0 0]"), "{}", pdf);
+        assert!(pdf.contains("/Decode [0 0.5019608]"), "{}", pdf);
+    }
+}
@@ -0,0 +1,145 @@
+//! CPU side pixel buffers
+
+use std::path::Path;
+
+use rgb::RGBA8;
+
+use super::save::{self, LoadError, SaveError};
+use crate::types::{Color, Scalar};
+
+/// Convert a linear color into sRGB encoded 8-bit
+/// components, as stored in textures and images.
+pub(crate) fn encode_color(color: Color) -> RGBA8 {
+    fn encode(c: Scalar) -> u8 {
+        let c = c.clamp(0.0, 1.0);
+        let c = if c <= 0.003_130_8 {
+            c * 12.92
+        }
+        else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        };
+
+        (c * 255.0).round() as u8
+    }
+
+    RGBA8::new(
+        encode(color.r),
+        encode(color.g),
+        encode(color.b),
+        (color.a.clamp(0.0, 1.0) * 255.0).round() as u8,
+    )
+}
+
+/// Inverse of [`encode_color`].
+pub(crate) fn decode_color(pixel: RGBA8) -> Color {
+    fn decode(c: u8) -> Scalar {
+        let c = c as Scalar / 255.0;
+        if c <= 0.040_45 {
+            c / 12.92
+        }
+        else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    Color::new(
+        decode(pixel.r),
+        decode(pixel.g),
+        decode(pixel.b),
+        pixel.a as Scalar / 255.0,
+    )
+}
+
+/// A copy of a frame's pixels, in row-major order starting
+/// from the top left.
+///
+/// The raw components are sRGB encoded, while [`get`] and
+/// [`set`] convert to and from the same color space used
+/// everywhere else in peach.
+///
+/// [`get`]: Pixels::get
+/// [`set`]: Pixels::set
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pixels {
+    width: u32,
+    height: u32,
+    data: Vec<RGBA8>,
+}
+
+impl Pixels {
+    pub(crate) fn from_raw(width: u32, height: u32, data: Vec<RGBA8>) -> Self {
+        debug_assert_eq!(data.len(), width as usize * height as usize);
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn index(&self, x: u32, y: u32) -> Option<usize> {
+        if x < self.width && y < self.height {
+            Some((y * self.width + x) as usize)
+        }
+        else {
+            None
+        }
+    }
+
+    /// Color of the pixel at `(x, y)`, or `None` when out
+    /// of bounds.
+    pub fn get(&self, x: u32, y: u32) -> Option<Color> {
+        self.index(x, y).map(|i| decode_color(self.data[i]))
+    }
+
+    /// Set the pixel at `(x, y)`. Out of bounds coordinates
+    /// are ignored.
+    pub fn set<C>(&mut self, x: u32, y: u32, color: C)
+    where
+        C: Into<Color>,
+    {
+        if let Some(i) = self.index(x, y) {
+            self.data[i] = encode_color(color.into());
+        }
+    }
+
+    pub fn as_raw(&self) -> &[RGBA8] {
+        &self.data
+    }
+
+    pub fn as_raw_mut(&mut self) -> &mut [RGBA8] {
+        &mut self.data
+    }
+
+    pub fn into_raw(self) -> Vec<RGBA8> {
+        self.data
+    }
+
+    pub fn to_colors(&self) -> Vec<Color> {
+        self.data.iter().copied().map(decode_color).collect()
+    }
+
+    /// Read a PNG from `path`. Palette, grayscale and
+    /// 16-bit images are converted to 8-bit RGBA.
+    pub fn load_png<P>(path: P) -> Result<Self, LoadError>
+    where
+        P: AsRef<Path>,
+    {
+        save::read_png(path.as_ref())
+    }
+
+    /// Write the pixels to `path` as an 8-bit RGBA PNG.
+    pub fn save_png<P>(&self, path: P) -> Result<(), SaveError>
+    where
+        P: AsRef<Path>,
+    {
+        save::write_png(self, path.as_ref())
+    }
+}
@@ -0,0 +1,477 @@
+//! Recording animations to disk
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use rgb::ComponentBytes;
+
+use super::graphics::Graphics;
+use super::pdf::PdfDocument;
+use super::pixels::Pixels;
+use super::save::{frame_path, write_png, SaveError};
+use crate::types::Size;
+
+/// Where recorded frames are written.
+#[derive(Debug, Clone)]
+pub enum RecordingFormat {
+    /// A numbered PNG per frame. The last run of `#` in the
+    /// pattern is replaced with the frame number, as with
+    /// [`Sketch::save_frame`](crate::sketch::Sketch::save_frame).
+    Png(String),
+    /// A single looping GIF, with each frame quantised to
+    /// its own palette.
+    Gif(PathBuf),
+    /// A video encoded by `ffmpeg`.
+    Video(Video),
+    /// A PDF with one vector page per frame.
+    Pdf(PathBuf),
+}
+
+/// Options for encoding video with a locally installed
+/// `ffmpeg`. Raw frames are streamed to it over stdin, so
+/// nothing is buffered on disk.
+#[derive(Debug, Clone)]
+pub struct Video {
+    /// Output file. The container is chosen by `ffmpeg`
+    /// from the extension, such as `.mp4` or `.webm`.
+    pub path: PathBuf,
+    /// Video codec, such as `"libx264"` or `"libvpx-vp9"`.
+    /// `ffmpeg` picks one for the container when `None`.
+    pub codec: Option<String>,
+    /// Target bitrate, such as `"8M"`.
+    pub bitrate: Option<String>,
+    /// Output pixel format. Defaults to `"yuv420p"`, which
+    /// most players support, but which needs the sketch
+    /// to have an even width and height.
+    pub pixel_format: Option<String>,
+    /// The `ffmpeg` binary to run.
+    pub ffmpeg: PathBuf,
+}
+
+impl Video {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self {
+            path: path.into(),
+            codec: None,
+            bitrate: None,
+            pixel_format: Some("yuv420p".into()),
+            ffmpeg: "ffmpeg".into(),
+        }
+    }
+
+    pub fn with_codec<S>(mut self, codec: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.codec = Some(codec.into());
+        self
+    }
+
+    pub fn with_bitrate<S>(mut self, bitrate: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.bitrate = Some(bitrate.into());
+        self
+    }
+
+    fn spawn(&self, width: u32, height: u32, framerate: u32) -> std::io::Result<Child> {
+        let mut command = Command::new(&self.ffmpeg);
+        command
+            .args(["-y", "-loglevel", "error"])
+            .args(["-f", "rawvideo", "-pixel_format", "rgba"])
+            .arg("-video_size")
+            .arg(format!("{}x{}", width, height))
+            .arg("-framerate")
+            .arg(framerate.to_string())
+            .args(["-i", "-"]);
+
+        if let Some(codec) = &self.codec {
+            command.arg("-c:v").arg(codec);
+        }
+        if let Some(bitrate) = &self.bitrate {
+            command.arg("-b:v").arg(bitrate);
+        }
+        if let Some(pixel_format) = &self.pixel_format {
+            command.arg("-pix_fmt").arg(pixel_format);
+        }
+
+        command
+            .arg(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+    }
+}
+
+/// Settings for capturing an animation.
+///
+/// While recording, the sketch runs on a simulated clock:
+/// every frame advances [`Sketch::get_time_since_start`] by
+/// exactly one frame at `framerate`, however long it takes
+/// to draw and encode. Recordings are therefore smooth even
+/// when the sketch can't run in real time.
+///
+/// [`Sketch::get_time_since_start`]: crate::sketch::Sketch::get_time_since_start
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub format: RecordingFormat,
+    /// Frames per second of simulated time. Zero is treated
+    /// as one.
+    pub framerate: u32,
+    /// Number of frames to record before stopping
+    /// automatically. When `None`, recording continues
+    /// until [`Sketch::stop_recording`] is called.
+    ///
+    /// [`Sketch::stop_recording`]: crate::sketch::Sketch::stop_recording
+    pub frames: Option<u64>,
+    /// Simulated time to record before stopping
+    /// automatically, converted to frames at `framerate`
+    /// when the recording starts. Ignored if `frames` is
+    /// set.
+    pub duration: Option<Duration>,
+}
+
+impl Recording {
+    /// Record a numbered PNG sequence, such as
+    /// `"frames/####.png"`.
+    pub fn png<S>(pattern: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::new(RecordingFormat::Png(pattern.into()))
+    }
+
+    /// Record an animated GIF.
+    pub fn gif<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self::new(RecordingFormat::Gif(path.into()))
+    }
+
+    /// Record a PDF, one page per frame.
+    pub fn pdf<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self::new(RecordingFormat::Pdf(path.into()))
+    }
+
+    /// Record a video with `ffmpeg`.
+    pub fn video(video: Video) -> Self {
+        Self::new(RecordingFormat::Video(video))
+    }
+
+    fn new(format: RecordingFormat) -> Self {
+        Self {
+            format,
+            framerate: 30,
+            frames: None,
+            duration: None,
+        }
+    }
+
+    pub fn with_framerate(mut self, framerate: u32) -> Self {
+        self.framerate = framerate.max(1);
+        self
+    }
+
+    pub fn with_frames(mut self, frames: u64) -> Self {
+        self.frames = Some(frames);
+        self.duration = None;
+        self
+    }
+
+    /// Record `duration` of simulated time, at whichever
+    /// framerate the recording ends up with.
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self.frames = None;
+        self
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs(1) / self.framerate()
+    }
+
+    fn framerate(&self) -> u32 {
+        self.framerate.max(1)
+    }
+
+    /// Number of frames to record, if limited.
+    fn frame_limit(&self) -> Option<u64> {
+        self.frames.or_else(|| {
+            let duration = self.duration?;
+            Some((duration.as_secs_f64() * self.framerate() as f64).ceil() as u64)
+        })
+    }
+}
+
+enum Output {
+    Png(String),
+    // Created from the size of the first frame, which every
+    // later frame has to match.
+    Gif(PathBuf, Option<gif::Encoder<BufWriter<File>>>),
+    Video(Video, Option<Child>),
+    Pdf(PathBuf, PdfDocument),
+}
+
+pub(crate) struct Recorder {
+    output: Output,
+    framerate: u32,
+    frame_duration: Duration,
+    frames: Option<u64>,
+    // Size of the first frame, for formats which can't
+    // change size part way through.
+    frame_size: Option<(u32, u32)>,
+
+    frame: u64,
+    start_time: Duration,
+}
+
+impl Recorder {
+    /// Start recording, with the simulated clock starting
+    /// from `start_time`.
+    pub fn new(recording: Recording, start_time: Duration) -> Self {
+        let frame_duration = recording.frame_duration();
+        let framerate = recording.framerate();
+        let frames = recording.frame_limit();
+        let output = match recording.format {
+            RecordingFormat::Png(pattern) => Output::Png(pattern),
+            RecordingFormat::Gif(path) => Output::Gif(path, None),
+            RecordingFormat::Video(video) => Output::Video(video, None),
+            RecordingFormat::Pdf(path) => Output::Pdf(path, PdfDocument::new()),
+        };
+
+        Self {
+            output,
+            framerate,
+            frame_duration,
+            frames,
+            frame_size: None,
+            frame: 0,
+            start_time,
+        }
+    }
+
+    /// Simulated time of the frame being drawn.
+    pub fn time(&self) -> Duration {
+        self.start_time + self.frame_duration * self.frame as u32
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.frames, Some(frames) if self.frame >= frames)
+    }
+
+    /// Whether frames are recorded from their pixels,
+    /// rather than their draw commands.
+    pub fn needs_pixels(&self) -> bool {
+        !matches!(self.output, Output::Pdf(..))
+    }
+
+    /// Record the draw commands of the next frame, for
+    /// vector formats.
+    pub fn record_commands(&mut self, gfx: &Graphics, size: Size) {
+        if let Output::Pdf(_, document) = &mut self.output {
+            document.add_page(gfx, size);
+            self.frame += 1;
+        }
+    }
+
+    /// Record draw commands which were flushed part way
+    /// through the next frame, for vector formats.
+    pub fn record_flushed(&mut self, gfx: &Graphics) {
+        if let Output::Pdf(_, document) = &mut self.output {
+            document.extend_page(gfx);
+        }
+    }
+
+    /// Check `pixels` is the same size as the first frame,
+    /// such as after the window was resized.
+    fn check_size(&mut self, pixels: &Pixels) -> Result<(), SaveError> {
+        let size = (pixels.width(), pixels.height());
+        let (width, height) = *self.frame_size.get_or_insert(size);
+
+        if size != (width, height) {
+            return Err(SaveError::FrameSize(width, height, size.0, size.1));
+        }
+        Ok(())
+    }
+
+    /// Write `pixels` as the next frame. GIFs and videos
+    /// can't change size, so frames which don't match the
+    /// first are an error.
+    pub fn record(&mut self, pixels: &Pixels) -> Result<(), SaveError> {
+        if matches!(self.output, Output::Gif(..) | Output::Video(..)) {
+            self.check_size(pixels)?;
+        }
+
+        match &mut self.output {
+            Output::Pdf(..) => return Ok(()),
+            Output::Png(pattern) => {
+                write_png(pixels, &frame_path(pattern, self.frame))?;
+            },
+            Output::Gif(path, encoder) => {
+                let (width, height) = match (
+                    u16::try_from(pixels.width()),
+                    u16::try_from(pixels.height()),
+                ) {
+                    (Ok(width), Ok(height)) => (width, height),
+                    _ => return Err(SaveError::GifSize(pixels.width(), pixels.height())),
+                };
+
+                let encoder = match encoder {
+                    Some(encoder) => encoder,
+                    None => {
+                        let file = BufWriter::new(File::create(path)?);
+                        let mut new = gif::Encoder::new(file, width, height, &[])?;
+                        new.set_repeat(gif::Repeat::Infinite)?;
+                        encoder.insert(new)
+                    },
+                };
+
+                let mut data = pixels.as_raw().as_bytes().to_vec();
+                let mut frame = gif::Frame::from_rgba_speed(width, height, &mut data, 10);
+                // GIF delays are in hundredths of a second, and
+                // a delay of zero plays at an unspecified speed.
+                let delay = (self.frame_duration.as_secs_f64() * 100.0).round() as u16;
+                frame.delay = delay.max(1);
+
+                encoder.write_frame(&frame)?;
+            },
+            Output::Video(video, child) => {
+                let child = match child {
+                    Some(child) => child,
+                    None => {
+                        child.insert(video.spawn(
+                            pixels.width(),
+                            pixels.height(),
+                            self.framerate,
+                        )?)
+                    },
+                };
+
+                let stdin = child.stdin.as_mut().ok_or(SaveError::FfmpegStdin)?;
+                stdin.write_all(pixels.as_raw().as_bytes())?;
+            },
+        }
+
+        self.frame += 1;
+        Ok(())
+    }
+
+    /// Finish writing the recording.
+    pub fn finish(self) -> Result<(), SaveError> {
+        match self.output {
+            Output::Gif(_, Some(encoder)) => {
+                encoder.into_inner()?.flush()?;
+            },
+            Output::Pdf(path, document) => {
+                document.save(path)?;
+            },
+            Output::Video(_, Some(mut child)) => {
+                // Closing stdin tells ffmpeg there are no more
+                // frames.
+                drop(child.stdin.take());
+
+                let status = child.wait()?;
+                if !status.success() {
+                    return Err(SaveError::Ffmpeg(status));
+                }
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_framerate_is_one() {
+        let recording = Recording {
+            framerate: 0,
+            ..Recording::png("####.png")
+        };
+
+        assert_eq!(recording.frame_duration(), Duration::from_secs(1));
+        assert_eq!(
+            recording
+                .with_duration(Duration::from_secs(2))
+                .frame_limit(),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn durations_use_the_final_framerate() {
+        let duration = Duration::from_millis(1500);
+        let before = Recording::png("####.png")
+            .with_duration(duration)
+            .with_framerate(60);
+        let after = Recording::png("####.png")
+            .with_framerate(60)
+            .with_duration(duration);
+
+        assert_eq!(before.frame_limit(), Some(90));
+        assert_eq!(after.frame_limit(), Some(90));
+        assert_eq!(after.with_frames(10).frame_limit(), Some(10));
+    }
+
+    #[test]
+    fn oversized_gif_is_an_error() {
+        let path = std::env::temp_dir().join("peach-oversized.gif");
+        let mut recorder = Recorder::new(Recording::gif(&path), Duration::ZERO);
+
+        let pixels = Pixels::from_raw(70_000, 1, vec![Default::default(); 70_000]);
+        assert!(matches!(
+            recorder.record(&pixels),
+            Err(SaveError::GifSize(70_000, 1))
+        ));
+    }
+
+    #[test]
+    fn resized_frames_are_an_error() {
+        let path = std::env::temp_dir().join("peach-resized.gif");
+        let mut recorder = Recorder::new(Recording::gif(&path), Duration::ZERO);
+
+        let pixels = Pixels::from_raw(4, 2, vec![Default::default(); 8]);
+        recorder.record(&pixels).unwrap();
+        recorder.record(&pixels).unwrap();
+
+        let resized = Pixels::from_raw(2, 4, vec![Default::default(); 8]);
+        assert!(matches!(
+            recorder.record(&resized),
+            Err(SaveError::FrameSize(4, 2, 2, 4))
+        ));
+        assert_eq!(recorder.frame, 2);
+    }
+
+    #[test]
+    fn fast_gifs_have_a_delay() {
+        let path = std::env::temp_dir().join("peach-fast.gif");
+        let recording = Recording::gif(&path).with_framerate(240);
+        let mut recorder = Recorder::new(recording, Duration::ZERO);
+
+        let pixels = Pixels::from_raw(1, 1, vec![Default::default(); 1]);
+        recorder.record(&pixels).unwrap();
+        recorder.finish().unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(File::open(&path).unwrap()).unwrap();
+        let frame = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!(frame.delay, 1);
+    }
+}
@@ -1,11 +1,49 @@
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Weak};
+
+use rgb::{ComponentBytes, RGBA8};
 use thiserror::Error;
 use wgpu::util::DeviceExt;
 use winit::dpi::PhysicalSize;
 use winit::window::Window;
 
-use super::graphics::{BufferData, Graphics};
+use super::buffer::GrowableBuffer;
+use super::cache::{TessellationCache, TessellationStats};
+use super::construct::Batch;
+use super::context::Context;
+use super::graphics::{self, BufferData, Graphics};
+use super::image::{
+    self, DirtyRegion, FilterMode, Image, ImageData, ImageId, MipLevel, Sampling, WrapMode,
+};
+use super::pixels::Pixels;
+use super::shape::{Shape, ShapeData, ShapeId, ShapeStyle};
+use super::target::{RenderTarget, Uniforms, TARGET_FORMAT};
 use crate::tess;
-use crate::types::{GpuScalar, RawVertex, Scalar, Transform, Vector};
+use crate::types::{Color, RawInstance, RawTransform, RawVertex, Scalar};
+
+/// Largest tile used by [`Renderer::render_scaled`], to
+/// keep the memory used by each tile reasonable.
+const MAX_TILE_SIZE: u32 = 4096;
+
+/// Largest frame [`Renderer::render_scaled`] will allocate,
+/// in bytes. Enough for a 32768x32768 frame.
+const MAX_SCALED_BYTES: u64 = 1 << 32;
+
+/// Number of frames a retained shape's style can go unused
+/// before its buffers are freed.
+const MAX_SHAPE_AGE: u64 = 120;
+
+/// Check that `requested` is a sample count the GPU can
+/// render with. wgpu only accepts render passes with 1 or 4
+/// samples, whatever the adapter, so 2 and 8 are errors
+/// rather than quietly changing the anti-aliasing.
+fn msaa_sample_count(requested: u32) -> Result<u32, RendererInitError> {
+    match requested {
+        1 | 4 => Ok(requested),
+        _ => Err(RendererInitError::MsaaSamples(requested)),
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum RendererInitError {
@@ -13,6 +51,8 @@ pub enum RendererInitError {
     RequestAdapter,
     #[error("Failed to request GPU Device")]
     RequestDevice,
+    #[error("Unsupported MSAA sample count {0}, expected 1 or 4")]
+    MsaaSamples(u32),
 }
 
 #[derive(Error, Debug)]
@@ -21,63 +61,275 @@ pub enum RenderError {
     SurfaceTexture(wgpu::SurfaceError),
     #[error("Error when constructing vertex buffers: {0:?}")]
     BufferConstruct(tess::TessellationError),
+    #[error("Failed to map readback buffer: {0}")]
+    BufferMap(wgpu::BufferAsyncError),
+    #[error("Pixel buffer size does not match the render target")]
+    PixelsSize,
+    #[error("A canvas can't draw its own image")]
+    CanvasFeedback,
+    #[error("A {0}x{1} canvas is too large to render at {2} times its size")]
+    ScaledSize(u32, u32, u32),
 }
 
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct Uniforms {
-    normalize: [GpuScalar; 16],
+/// The size of a `width` by `height` canvas rendered at
+/// `scale` times its size, checked before any pixels are
+/// allocated for it. Frames larger than
+/// [`MAX_SCALED_BYTES`], or than a `Vec` can hold, are an
+/// error.
+pub(super) fn scaled_size(width: u32, height: u32, scale: u32) -> Result<(u32, u32), RenderError> {
+    let error = || RenderError::ScaledSize(width, height, scale);
+
+    let w = width.checked_mul(scale).ok_or_else(error)?;
+    let h = height.checked_mul(scale).ok_or_else(error)?;
+    let bytes = (w as u64)
+        .checked_mul(h as u64)
+        .and_then(|pixels| pixels.checked_mul(std::mem::size_of::<RGBA8>() as u64))
+        .ok_or_else(error)?;
+
+    if bytes > MAX_SCALED_BYTES.min(isize::MAX as u64) {
+        return Err(error());
+    }
+    Ok((w, h))
 }
 
-unsafe impl bytemuck::Pod for Uniforms {}
-unsafe impl bytemuck::Zeroable for Uniforms {}
+/// How finished frames are shown on the window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PresentMode {
+    /// Wait for the display's vertical blank, so frames
+    /// never tear. The framerate is capped at the refresh
+    /// rate.
+    #[default]
+    Vsync,
+    /// Show frames as soon as they are ready, for the
+    /// lowest latency and uncapped framerates, such as for
+    /// benchmarks. Frames may tear.
+    Immediate,
+    /// Replace any frame waiting for the vertical blank
+    /// with a newer one. Frames don't tear, and the
+    /// framerate isn't capped.
+    Mailbox,
+}
 
-impl Uniforms {
-    fn generate(width: u32, height: u32) -> Self {
-        let width = width as Scalar;
-        let height = height as Scalar;
+impl From<PresentMode> for wgpu::PresentMode {
+    fn from(mode: PresentMode) -> Self {
+        match mode {
+            PresentMode::Vsync => wgpu::PresentMode::Fifo,
+            PresentMode::Immediate => wgpu::PresentMode::Immediate,
+            PresentMode::Mailbox => wgpu::PresentMode::Mailbox,
+        }
+    }
+}
 
-        let transform =
-            Transform::scale(2.0 / width, -2.0 / height).then_translate(Vector::new(-1.0, 1.0));
+impl From<FilterMode> for wgpu::FilterMode {
+    fn from(filter: FilterMode) -> Self {
+        match filter {
+            FilterMode::Nearest => wgpu::FilterMode::Nearest,
+            FilterMode::Linear => wgpu::FilterMode::Linear,
+        }
+    }
+}
 
-        Self {
-            normalize: transform.cast::<GpuScalar>().to_3d().to_array(),
+impl From<WrapMode> for wgpu::AddressMode {
+    fn from(wrap: WrapMode) -> Self {
+        match wrap {
+            WrapMode::Clamp => wgpu::AddressMode::ClampToEdge,
+            WrapMode::Repeat => wgpu::AddressMode::Repeat,
+            WrapMode::Mirror => wgpu::AddressMode::MirrorRepeat,
         }
     }
 }
 
-pub(crate) struct Renderer {
+enum GpuTexture {
+    /// Uploaded from an image's CPU side pixels.
+    Uploaded {
+        texture: wgpu::Texture,
+        mip_levels: u32,
+        // Kept so that edits only have to rebuild the parts
+        // of each level they change.
+        mips: Vec<MipLevel>,
+    },
+    /// Rendered into by a canvas.
+    Target(RenderTarget),
+}
+
+impl GpuTexture {
+    fn texture(&self) -> &wgpu::Texture {
+        match self {
+            GpuTexture::Uploaded { texture, .. } => texture,
+            GpuTexture::Target(target) => &target.texture,
+        }
+    }
+
+    fn mip_levels(&self) -> u32 {
+        match self {
+            GpuTexture::Uploaded { mip_levels, .. } => *mip_levels,
+            GpuTexture::Target(_) => 1,
+        }
+    }
+}
+
+/// GPU side copy of an [`Image`], kept alive for as long as
+/// the image data itself.
+struct GpuImage {
+    data: Weak<ImageData>,
+    texture: GpuTexture,
+    bind_groups: HashMap<Sampling, wgpu::BindGroup>,
+    // Version of the image's pixels in the texture.
+    version: u64,
+}
+
+/// GPU side copy of a [`Shape`] drawn with one style,
+/// tessellated once and kept alive for as long as the shape
+/// itself.
+struct GpuShape {
+    data: Weak<ShapeData>,
+    style: ShapeStyle,
+    last_used: u64,
+    // `None` when the shape has no geometry.
+    buffers: Option<ShapeBuffers>,
+    batches: Vec<Batch>,
+}
+
+struct ShapeBuffers {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    // Kept alive for the bind group.
+    _transform_buffer: wgpu::Buffer,
+    transforms_bind_group: wgpu::BindGroup,
+}
+
+/// Window surface which the canvas is copied onto when
+/// presenting.
+struct Presenter {
     surface: wgpu::Surface,
+    config: wgpu::SurfaceConfiguration,
+
+    canvas_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl Presenter {
+    fn new(
+        surface: wgpu::Surface,
+        adapter: &wgpu::Adapter,
+        device: &wgpu::Device,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        samplers: &mut HashMap<Sampling, wgpu::Sampler>,
+        canvas: &RenderTarget,
+        present_mode: PresentMode,
+    ) -> Self {
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            format: surface
+                .get_preferred_format(adapter)
+                .unwrap_or(wgpu::TextureFormat::Bgra8UnormSrgb),
+            width: canvas.width,
+            height: canvas.height,
+            present_mode: present_mode.into(),
+        };
+        surface.configure(device, &config);
+
+        let pipeline =
+            Renderer::create_blit_pipeline(device, texture_bind_group_layout, config.format, 1);
 
+        let canvas_bind_group =
+            Renderer::create_canvas_bind_group(device, texture_bind_group_layout, samplers, canvas);
+
+        Self {
+            surface,
+            config,
+            canvas_bind_group,
+            pipeline,
+        }
+    }
+}
+
+pub(crate) struct Renderer {
     device: wgpu::Device,
     queue: wgpu::Queue,
 
-    config: wgpu::SurfaceConfiguration,
+    // `None` when rendering headless.
+    presenter: Option<Presenter>,
 
-    uniforms_buf: wgpu::Buffer,
-    bind_group: wgpu::BindGroup,
+    uniforms_bind_group_layout: wgpu::BindGroupLayout,
+
+    // Everything is drawn into the canvas, which is then
+    // copied to the surface when presenting. Unlike surface
+    // textures, the canvas keeps its contents between frames.
+    canvas: RenderTarget,
+
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    samplers: HashMap<Sampling, wgpu::Sampler>,
+    images: HashMap<ImageId, GpuImage>,
+    // 1x1 white image bound for untextured geometry.
+    blank: Image,
+    default_sampling: Sampling,
+
+    // Reused every frame, and only reallocated when a frame
+    // has more geometry than they can hold.
+    vertex_buffer: GrowableBuffer,
+    index_buffer: GrowableBuffer,
+    instance_buffer: GrowableBuffer,
+    // Transforms are applied in the vertex shader, so the
+    // same geometry can be drawn under any transform
+    // without transforming it on the CPU.
+    transform_buffer: GrowableBuffer,
+    transforms_bind_group_layout: wgpu::BindGroupLayout,
+    transforms_bind_group: wgpu::BindGroup,
+
+    // Number of samples per pixel, and the pipeline which
+    // copies a target back into its multisampled texture,
+    // when using MSAA.
+    msaa_samples: u32,
+    msaa_reload_pipeline: Option<wgpu::RenderPipeline>,
+
+    tessellation_cache: TessellationCache,
+
+    // Every style of each shape drawn recently.
+    shapes: HashMap<ShapeId, Vec<GpuShape>>,
+    frame: u64,
 
     pipeline: wgpu::RenderPipeline,
 }
 
 impl Renderer {
-    pub async fn new(window: &Window) -> Result<Self, RendererInitError> {
-        let physical = window.inner_size();
-
-        let PhysicalSize { width, height } = physical;
+    /// Create a renderer presenting to `window`, or a
+    /// headless renderer when there is no window.
+    pub async fn new(
+        window: Option<&Window>,
+        size: PhysicalSize<u32>,
+        default_sampling: Sampling,
+        msaa_samples: u32,
+        present_mode: PresentMode,
+    ) -> Result<Self, RendererInitError> {
+        let PhysicalSize { width, height } = size;
+        let msaa_samples = msaa_sample_count(msaa_samples)?;
 
         let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
 
-        let surface = unsafe { instance.create_surface(window) };
+        let surface = window.map(|window| unsafe { instance.create_surface(window) });
 
-        let adapter = instance
+        let mut adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
-                compatible_surface: Some(&surface),
+                compatible_surface: surface.as_ref(),
                 power_preference: wgpu::PowerPreference::HighPerformance,
                 ..Default::default()
             })
-            .await
-            .ok_or(RendererInitError::RequestAdapter)?;
+            .await;
+
+        // Headless rendering should still work on machines
+        // without a GPU, using a software adapter if there is
+        // one.
+        if adapter.is_none() && surface.is_none() {
+            adapter = instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    force_fallback_adapter: true,
+                    ..Default::default()
+                })
+                .await;
+        }
+
+        let adapter = adapter.ok_or(RendererInitError::RequestAdapter)?;
 
         let (device, queue) = adapter
             .request_device(
@@ -91,54 +343,70 @@ impl Renderer {
             .await
             .or(Err(RendererInitError::RequestDevice))?;
 
-        let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface
-                .get_preferred_format(&adapter)
-                .unwrap_or_else(|| wgpu::TextureFormat::Bgra8UnormSrgb),
-            width,
-            height,
-            present_mode: wgpu::PresentMode::Fifo,
-        };
-        surface.configure(&device, &config);
-
-        let uniforms = Uniforms::generate(width, height);
         let uniforms_size = std::mem::size_of::<Uniforms>() as wgpu::BufferAddress;
-        let uniforms_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("uniforms buffer"),
-            contents: bytemuck::bytes_of(&uniforms),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+        let uniforms_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("uniforms bind group layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(uniforms_size),
+                    },
+                    count: None,
+                }],
+            });
 
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("uniforms bind group layout"),
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: wgpu::BufferSize::new(uniforms_size),
-                },
-                count: None,
-            }],
-        });
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("texture bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+            });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("uniforms bind group"),
-            layout: &bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: uniforms_buf.as_entire_binding(),
-            }],
-        });
+        let transforms_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("transforms bind group layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
 
         let shader = device.create_shader_module(&wgpu::include_wgsl!("shader.wgsl"));
 
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: None,
-                bind_group_layouts: &[&bind_group_layout],
+                bind_group_layouts: &[
+                    &uniforms_bind_group_layout,
+                    &texture_bind_group_layout,
+                    &transforms_bind_group_layout,
+                ],
                 push_constant_ranges: &[],
             });
 
@@ -149,92 +417,783 @@ impl Renderer {
             vertex: wgpu::VertexState {
                 module: &shader,
                 entry_point: "vs_main",
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: core::mem::size_of::<RawVertex>() as u64,
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &wgpu::vertex_attr_array![
-                        0 => Float32x2,
-                        1 => Float32x4,
-                    ],
-                }],
+                buffers: &[
+                    wgpu::VertexBufferLayout {
+                        array_stride: core::mem::size_of::<RawVertex>() as u64,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array![
+                            0 => Float32x2,
+                            1 => Float32x4,
+                            2 => Float32x2,
+                            3 => Uint32,
+                            7 => Float32x2,
+                            8 => Float32x2,
+                        ],
+                    },
+                    wgpu::VertexBufferLayout {
+                        array_stride: core::mem::size_of::<RawInstance>() as u64,
+                        step_mode: wgpu::VertexStepMode::Instance,
+                        attributes: &wgpu::vertex_attr_array![
+                            4 => Float32x4,
+                            5 => Float32x4,
+                            6 => Float32x4,
+                        ],
+                    },
+                ],
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
                 entry_point: "fs_main",
-                targets: &[config.format.into()],
+                targets: &[wgpu::ColorTargetState {
+                    format: TARGET_FORMAT,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                }],
             }),
             primitive: wgpu::PrimitiveState::default(),
-            multisample: wgpu::MultisampleState::default(),
+            multisample: wgpu::MultisampleState {
+                count: msaa_samples,
+                ..Default::default()
+            },
             depth_stencil: None,
         });
 
+        let msaa_reload_pipeline = (msaa_samples > 1).then(|| {
+            Self::create_blit_pipeline(
+                &device,
+                &texture_bind_group_layout,
+                TARGET_FORMAT,
+                msaa_samples,
+            )
+        });
+
+        let mut samplers = HashMap::new();
+        // Used for copying whole targets, which never need
+        // filtering.
+        samplers.insert(
+            Sampling::pixelated(),
+            Self::create_sampler(&device, Sampling::pixelated()),
+        );
+
+        let canvas = RenderTarget::new(
+            &device,
+            &uniforms_bind_group_layout,
+            width,
+            height,
+            msaa_samples,
+        );
+
+        let presenter = surface.map(|surface| {
+            Presenter::new(
+                surface,
+                &adapter,
+                &device,
+                &texture_bind_group_layout,
+                &mut samplers,
+                &canvas,
+                present_mode,
+            )
+        });
+
+        let blank = Image::from_rgba8(1, 1, vec![RGBA8::new(255, 255, 255, 255)])
+            .expect("blank image has exactly one pixel");
+
+        let vertex_buffer =
+            GrowableBuffer::new(&device, "Peach::Vertex", wgpu::BufferUsages::VERTEX);
+        let index_buffer = GrowableBuffer::new(&device, "Peach::Index", wgpu::BufferUsages::INDEX);
+        let instance_buffer =
+            GrowableBuffer::new(&device, "Peach::Instance", wgpu::BufferUsages::VERTEX);
+        let transform_buffer =
+            GrowableBuffer::new(&device, "Peach::Transforms", wgpu::BufferUsages::STORAGE);
+        let transforms_bind_group = Self::create_transforms_bind_group(
+            &device,
+            &transforms_bind_group_layout,
+            &transform_buffer,
+        );
+
         Ok(Self {
-            surface,
             device,
             queue,
-            config,
-            uniforms_buf,
-            bind_group,
+            presenter,
+            uniforms_bind_group_layout,
+            canvas,
+            texture_bind_group_layout,
+            samplers,
+            images: HashMap::new(),
+            blank,
+            default_sampling,
+            vertex_buffer,
+            index_buffer,
+            instance_buffer,
+            transform_buffer,
+            transforms_bind_group_layout,
+            transforms_bind_group,
+            msaa_samples,
+            msaa_reload_pipeline,
+            tessellation_cache: TessellationCache::default(),
+            shapes: HashMap::new(),
+            frame: 0,
             pipeline,
         })
     }
 
+    fn create_transforms_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffer: &GrowableBuffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Peach::TransformsBindGroup"),
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.buffer().as_entire_binding(),
+            }],
+        })
+    }
+
+    /// A pipeline which copies a whole texture onto a
+    /// target of `format`, with a single triangle covering
+    /// it.
+    fn create_blit_pipeline(
+        device: &wgpu::Device,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        format: wgpu::TextureFormat,
+        samples: u32,
+    ) -> wgpu::RenderPipeline {
+        let blit_shader = device.create_shader_module(&wgpu::include_wgsl!("blit.wgsl"));
+
+        let blit_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Peach::Blit"),
+            multiview: None,
+            layout: Some(&blit_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &blit_shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &blit_shader,
+                entry_point: "fs_main",
+                targets: &[format.into()],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            multisample: wgpu::MultisampleState {
+                count: samples,
+                ..Default::default()
+            },
+            depth_stencil: None,
+        })
+    }
+
+    fn create_canvas_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        samplers: &mut HashMap<Sampling, wgpu::Sampler>,
+        canvas: &RenderTarget,
+    ) -> wgpu::BindGroup {
+        // The canvas is always the same size as the surface, so
+        // no filtering is needed.
+        let sampling = Sampling::pixelated();
+        let sampler = samplers
+            .entry(sampling)
+            .or_insert_with(|| Self::create_sampler(device, sampling));
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Peach::CanvasBindGroup"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&canvas.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+        })
+    }
+
+    fn create_sampler(device: &wgpu::Device, sampling: Sampling) -> wgpu::Sampler {
+        let filter = sampling.filter.into();
+        let wrap = sampling.wrap.into();
+
+        device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Peach::Sampler"),
+            address_mode_u: wrap,
+            address_mode_v: wrap,
+            address_mode_w: wrap,
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_filter: if sampling.mipmaps {
+                filter
+            }
+            else {
+                wgpu::FilterMode::Nearest
+            },
+            lod_max_clamp: if sampling.mipmaps { f32::MAX } else { 0.0 },
+            ..Default::default()
+        })
+    }
+
+    fn create_texture(&self, data: &ImageData, pixels: &[RGBA8], mipmaps: bool) -> GpuTexture {
+        let mip_levels = if mipmaps {
+            image::mip_level_count(data.width, data.height)
+        }
+        else {
+            1
+        };
+
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Peach::Image"),
+            size: wgpu::Extent3d {
+                width: data.width,
+                height: data.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: mip_levels,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        });
+
+        let mips = if mipmaps {
+            image::generate_mips(data.width, data.height, pixels)
+        }
+        else {
+            Vec::new()
+        };
+
+        // A new texture always needs every pixel uploaded.
+        let full = |width, height| {
+            DirtyRegion {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            }
+        };
+        let queue = &self.queue;
+        Self::write_texture_region(
+            queue,
+            &texture,
+            0,
+            data.width,
+            pixels,
+            full(data.width, data.height),
+        );
+        for (level, (width, height, pixels)) in mips.iter().enumerate() {
+            let region = full(*width, *height);
+            Self::write_texture_region(queue, &texture, level as u32 + 1, *width, pixels, region);
+        }
+
+        GpuTexture::Uploaded {
+            texture,
+            mip_levels,
+            mips,
+        }
+    }
+
+    /// Upload a changed `region` of `pixels`, along with
+    /// the parts of each mip level built from it.
+    fn upload_region(
+        queue: &wgpu::Queue,
+        texture: &mut GpuTexture,
+        data: &ImageData,
+        pixels: &[RGBA8],
+        region: DirtyRegion,
+    ) {
+        let (texture, mips) = match texture {
+            GpuTexture::Uploaded { texture, mips, .. } => (texture, mips),
+            GpuTexture::Target(_) => unreachable!("canvas images have no pixels to upload"),
+        };
+
+        Self::write_texture_region(queue, texture, 0, data.width, pixels, region);
+
+        let regions = image::update_mips(data.width, data.height, pixels, mips, region);
+        for (level, ((width, _, pixels), region)) in mips.iter().zip(regions).enumerate() {
+            if region.width > 0 && region.height > 0 {
+                Self::write_texture_region(
+                    queue,
+                    texture,
+                    level as u32 + 1,
+                    *width,
+                    pixels,
+                    region,
+                );
+            }
+        }
+    }
+
+    fn write_texture_region(
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        mip_level: u32,
+        stride: u32,
+        pixels: &[RGBA8],
+        region: DirtyRegion,
+    ) {
+        let DirtyRegion {
+            x,
+            y,
+            width,
+            height,
+        } = region;
+
+        // Point the layout at the first dirty pixel, and step
+        // through full rows, so the region doesn't have to be
+        // copied out of the image first.
+        let first =
+            y as wgpu::BufferAddress * stride as wgpu::BufferAddress + x as wgpu::BufferAddress;
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            pixels.as_bytes(),
+            wgpu::ImageDataLayout {
+                offset: 4 * first,
+                bytes_per_row: NonZeroU32::new(4 * stride),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// Make sure `image` has a texture and a bind group for
+    /// `sampling`, creating them if needed.
+    fn prepare_image(&mut self, image: &Image, sampling: Sampling) {
+        let data = image.data();
+
+        let contents = data.lock();
+
+        let needs_texture = match self.images.get(&data.id) {
+            Some(gpu) => {
+                let mip_levels = image::mip_level_count(data.width, data.height);
+                contents.is_some() && sampling.mipmaps && gpu.texture.mip_levels() < mip_levels
+            },
+            None => true,
+        };
+
+        match contents {
+            Some(contents) if needs_texture => {
+                let texture = self.create_texture(data, &contents.pixels, sampling.mipmaps);
+                self.insert_image(data, texture, contents.version());
+            },
+            Some(contents) => {
+                let gpu = self.images.get_mut(&data.id).unwrap();
+                if let Some(region) = contents.changed_since(gpu.version) {
+                    Self::upload_region(
+                        &self.queue,
+                        &mut gpu.texture,
+                        data,
+                        &contents.pixels,
+                        region,
+                    );
+                    gpu.version = contents.version();
+                }
+            },
+            None if needs_texture => {
+                let target = RenderTarget::new(
+                    &self.device,
+                    &self.uniforms_bind_group_layout,
+                    data.width,
+                    data.height,
+                    self.msaa_samples,
+                );
+                self.insert_image(data, GpuTexture::Target(target), 0);
+            },
+            None => {},
+        }
+
+        let device = &self.device;
+        let sampler = self
+            .samplers
+            .entry(sampling)
+            .or_insert_with(|| Self::create_sampler(device, sampling));
+
+        let layout = &self.texture_bind_group_layout;
+        let gpu = self.images.get_mut(&data.id).unwrap();
+        let texture = gpu.texture.texture();
+
+        gpu.bind_groups.entry(sampling).or_insert_with(|| {
+            let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Peach::ImageBindGroup"),
+                layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                ],
+            })
+        });
+    }
+
+    fn insert_image(&mut self, data: &Arc<ImageData>, texture: GpuTexture, version: u64) {
+        self.images.insert(
+            data.id,
+            GpuImage {
+                data: Arc::downgrade(data),
+                texture,
+                bind_groups: HashMap::new(),
+                version,
+            },
+        );
+    }
+
+    fn image_bind_group(&self, image: Option<&Image>) -> &wgpu::BindGroup {
+        let image = image.unwrap_or(&self.blank);
+        let sampling = self.resolve_sampling(image);
+
+        &self.images[&image.id()].bind_groups[&sampling]
+    }
+
+    fn resolve_sampling(&self, image: &Image) -> Sampling {
+        image.sampling().unwrap_or(self.default_sampling)
+    }
+
+    pub fn tessellation_stats(&self) -> TessellationStats {
+        self.tessellation_cache.stats()
+    }
+
+    pub fn size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.canvas.width, self.canvas.height)
+    }
+
     pub fn resize(&mut self, size: PhysicalSize<u32>) {
         if size.width > 0 && size.height > 0 {
-            self.config.width = size.width;
-            self.config.height = size.height;
+            self.canvas = RenderTarget::new(
+                &self.device,
+                &self.uniforms_bind_group_layout,
+                size.width,
+                size.height,
+                self.msaa_samples,
+            );
 
-            let uniforms = Uniforms::generate(size.width, size.height);
-            self.queue
-                .write_buffer(&self.uniforms_buf, 0, &bytemuck::bytes_of(&uniforms));
+            if let Some(presenter) = &mut self.presenter {
+                presenter.config.width = size.width;
+                presenter.config.height = size.height;
+                presenter.surface.configure(&self.device, &presenter.config);
 
-            self.surface.configure(&self.device, &self.config);
+                presenter.canvas_bind_group = Self::create_canvas_bind_group(
+                    &self.device,
+                    &self.texture_bind_group_layout,
+                    &mut self.samplers,
+                    &self.canvas,
+                );
+            }
+        }
+    }
+
+    /// Reconfigure the window surface with `mode`. Modes
+    /// the surface doesn't support fall back to
+    /// [`PresentMode::Vsync`]. Does nothing when headless.
+    pub fn set_present_mode(&mut self, mode: PresentMode) {
+        if let Some(presenter) = &mut self.presenter {
+            presenter.config.present_mode = mode.into();
+            presenter.surface.configure(&self.device, &presenter.config);
+        }
+    }
+
+    /// Draw `gfx` into the canvas, without presenting it.
+    pub fn draw(&mut self, gfx: Graphics) -> Result<(), RenderError> {
+        self.draw_to(None, gfx)
+    }
+
+    /// Draw `gfx` into the offscreen target behind `image`.
+    pub fn draw_offscreen(&mut self, image: &Image, gfx: Graphics) -> Result<(), RenderError> {
+        self.prepare_image(image, self.resolve_sampling(image));
+        self.draw_to(Some(image.id()), gfx)
+    }
+
+    fn target(&self, target: Option<ImageId>) -> &RenderTarget {
+        match target {
+            None => &self.canvas,
+            Some(id) => {
+                match &self.images[&id].texture {
+                    GpuTexture::Target(target) => target,
+                    GpuTexture::Uploaded { .. } => {
+                        unreachable!("only canvas images are drawn into")
+                    },
+                }
+            },
         }
     }
 
-    pub fn render(&mut self, gfx: Graphics) -> Result<(), RenderError> {
+    fn draw_to(&mut self, target: Option<ImageId>, gfx: Graphics) -> Result<(), RenderError> {
         let clear_color = gfx.clear_color;
+        let data = self.prepare(gfx, target)?;
+        self.upload_buffers(&data);
 
-        let BufferData { vertices, indices } = gfx
-            .construct_buffer_data()
-            .or_else(|err| Err(RenderError::BufferConstruct(err)))?;
-        let indice_count = indices.len();
+        self.draw_batches(self.target(target), &data.batches, clear_color);
 
-        let vertex_buffer = self
-            .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Peach::Vertex"),
-                contents: bytemuck::cast_slice(vertices.as_slice()),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
+        Ok(())
+    }
 
-        let index_buffer = self
+    /// Render `gfx` at `scale` times the canvas size, and
+    /// read back the result. The frame is split into tiles
+    /// no larger than the GPU allows, which each see a
+    /// different region of the scaled frame.
+    pub fn render_scaled(&mut self, gfx: Graphics, scale: u32) -> Result<Pixels, RenderError> {
+        let scale = scale.max(1);
+        let (width, height) = scaled_size(self.canvas.width, self.canvas.height, scale)?;
+        let tile_size = self
             .device
-            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Peach::Index"),
-                contents: bytemuck::cast_slice(indices.as_slice()),
-                usage: wgpu::BufferUsages::INDEX,
-            });
+            .limits()
+            .max_texture_dimension_2d
+            .min(MAX_TILE_SIZE);
 
-        let output = self
-            .surface
-            .get_current_texture()
-            .or_else(|err| Err(RenderError::SurfaceTexture(err)))?;
+        let clear_color = gfx.clear_color;
+        let data = self.prepare(gfx, None)?;
+        self.upload_buffers(&data);
 
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        let mut output = vec![RGBA8::default(); width as usize * height as usize];
+
+        for y in (0..height).step_by(tile_size as usize) {
+            for x in (0..width).step_by(tile_size as usize) {
+                let tile_width = tile_size.min(width - x);
+                let tile_height = tile_size.min(height - y);
+
+                let uniforms = Uniforms::tile(x, y, tile_width, tile_height, scale as Scalar);
+                let tile = RenderTarget::with_uniforms(
+                    &self.device,
+                    &self.uniforms_bind_group_layout,
+                    tile_width,
+                    tile_height,
+                    self.msaa_samples,
+                    uniforms,
+                );
+
+                self.draw_batches(&tile, &data.batches, clear_color);
+                let pixels = tile.read_pixels(&self.device, &self.queue)?;
+
+                // Stitch the tile into the output.
+                for (row, tile_row) in pixels.as_raw().chunks(tile_width as usize).enumerate() {
+                    let start = (y as usize + row) * width as usize + x as usize;
+                    output[start..start + tile_width as usize].copy_from_slice(tile_row);
+                }
+            }
+        }
+
+        Ok(Pixels::from_raw(width, height, output))
+    }
+
+    /// Build the buffer data of `gfx`, and upload every
+    /// image it uses.
+    fn prepare(
+        &mut self,
+        gfx: Graphics,
+        target: Option<ImageId>,
+    ) -> Result<BufferData, RenderError> {
+        let data = gfx
+            .construct_buffer_data(&mut self.tessellation_cache, true)
+            .or_else(|err| Err(RenderError::BufferConstruct(err)))?;
 
+        // Drop textures of images which no longer exist.
+        self.images.retain(|_, gpu| gpu.data.strong_count() > 0);
+
+        let mut images = Vec::new();
+        for batch in data.batches.iter() {
+            match &batch.shape {
+                Some(shape) => {
+                    let gpu = self.prepare_shape(shape)?;
+                    images.extend(gpu.batches.iter().map(|batch| batch.image.clone()));
+                },
+                None => images.push(batch.image.clone()),
+            }
+        }
+
+        let blank = self.blank.clone();
+        for image in images.iter() {
+            let image = image.as_ref().unwrap_or(&blank);
+            if Some(image.id()) == target {
+                return Err(RenderError::CanvasFeedback);
+            }
+
+            self.prepare_image(image, self.resolve_sampling(image));
+        }
+
+        Ok(data)
+    }
+
+    /// Find the retained buffers of `shape` with its
+    /// current style, tessellating it if this style hasn't
+    /// been drawn recently.
+    fn prepare_shape(&mut self, shape: &Shape) -> Result<&GpuShape, RenderError> {
+        let frame = self.frame;
+        let styles = self.shapes.entry(shape.id()).or_default();
+
+        let index = match styles.iter().position(|gpu| gpu.style == shape.style()) {
+            Some(index) => index,
+            None => {
+                // The whole mesh is kept, so there's no use in
+                // caching its parts as well.
+                let mut data = graphics::build_buffer_data(
+                    &shape.styled_commands(),
+                    Context::default(),
+                    &mut TessellationCache::default(),
+                    false,
+                )
+                .map_err(RenderError::BufferConstruct)?;
+
+                let buffers = if data.batches.is_empty() {
+                    None
+                }
+                else {
+                    Some(Self::create_shape_buffers(
+                        &self.device,
+                        &self.transforms_bind_group_layout,
+                        &data,
+                    ))
+                };
+
+                styles.push(GpuShape {
+                    data: Arc::downgrade(shape.data()),
+                    style: shape.style(),
+                    last_used: frame,
+                    buffers,
+                    batches: std::mem::take(&mut data.batches),
+                });
+                styles.len() - 1
+            },
+        };
+
+        let gpu = &mut styles[index];
+        gpu.last_used = frame;
+        Ok(gpu)
+    }
+
+    fn create_shape_buffers(
+        device: &wgpu::Device,
+        transforms_bind_group_layout: &wgpu::BindGroupLayout,
+        data: &BufferData,
+    ) -> ShapeBuffers {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Peach::ShapeVertex"),
+            contents: bytemuck::cast_slice(data.vertices.as_slice()),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Peach::ShapeIndex"),
+            contents: bytemuck::cast_slice(data.indices.as_slice()),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        let transforms: Vec<RawTransform> = data
+            .transforms
+            .iter()
+            .map(|transform| RawTransform::from(*transform))
+            .collect();
+        let transform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Peach::ShapeTransforms"),
+            contents: bytemuck::cast_slice(transforms.as_slice()),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let transforms_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Peach::ShapeTransformsBindGroup"),
+            layout: transforms_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: transform_buffer.as_entire_binding(),
+            }],
+        });
+
+        ShapeBuffers {
+            vertex_buffer,
+            index_buffer,
+            _transform_buffer: transform_buffer,
+            transforms_bind_group,
+        }
+    }
+
+    fn gpu_shape(&self, shape: &Shape) -> Option<&GpuShape> {
+        self.shapes
+            .get(&shape.id())?
+            .iter()
+            .find(|gpu| gpu.style == shape.style())
+    }
+
+    fn upload_buffers(&mut self, data: &BufferData) {
+        self.vertex_buffer.write(
+            &self.device,
+            &self.queue,
+            bytemuck::cast_slice(data.vertices.as_slice()),
+        );
+        self.index_buffer.write(
+            &self.device,
+            &self.queue,
+            bytemuck::cast_slice(data.indices.as_slice()),
+        );
+        self.instance_buffer.write(
+            &self.device,
+            &self.queue,
+            bytemuck::cast_slice(data.instances.as_slice()),
+        );
+
+        let transforms: Vec<RawTransform> = data
+            .transforms
+            .iter()
+            .map(|transform| RawTransform::from(*transform))
+            .collect();
+        let grew = self.transform_buffer.write(
+            &self.device,
+            &self.queue,
+            bytemuck::cast_slice(transforms.as_slice()),
+        );
+        if grew {
+            self.transforms_bind_group = Self::create_transforms_bind_group(
+                &self.device,
+                &self.transforms_bind_group_layout,
+                &self.transform_buffer,
+            );
+        }
+    }
+
+    fn draw_batches(&self, target: &RenderTarget, batches: &[Batch], clear_color: Option<Color>) {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
 
+        // Geometry is drawn into the multisampled texture,
+        // which keeps its samples between frames. If the
+        // target was written to directly since, copy it back
+        // first so the frame is drawn on top of it.
+        if target.msaa_stale.replace(false) && clear_color.is_none() {
+            self.reload_multisampled(&mut encoder, target);
+        }
+        let (view, resolve_target) = match &target.msaa_view {
+            Some(msaa_view) => (msaa_view, Some(&target.view)),
+            None => (&target.view, None),
+        };
+
         {
             let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
                 color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
+                    view,
+                    resolve_target,
                     ops: wgpu::Operations {
                         load: if let Some(color) = clear_color {
                             wgpu::LoadOp::Clear(wgpu::Color {
@@ -253,11 +1212,149 @@ impl Renderer {
                 depth_stencil_attachment: None,
             });
             rpass.set_pipeline(&self.pipeline);
-            rpass.set_bind_group(0, &self.bind_group, &[]);
-            rpass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-            rpass.set_vertex_buffer(0, vertex_buffer.slice(..));
+            rpass.set_bind_group(0, &target.bind_group, &[]);
+            rpass.set_bind_group(2, &self.transforms_bind_group, &[]);
+            rpass.set_index_buffer(self.index_buffer.slice(), wgpu::IndexFormat::Uint32);
+            rpass.set_vertex_buffer(0, self.vertex_buffer.slice());
+            rpass.set_vertex_buffer(1, self.instance_buffer.slice());
+
+            for batch in batches.iter() {
+                let shape = match &batch.shape {
+                    Some(shape) => shape,
+                    None => {
+                        let bind_group = self.image_bind_group(batch.image.as_ref());
+                        rpass.set_bind_group(1, bind_group, &[]);
+                        rpass.draw_indexed(batch.indices.clone(), 0, batch.instances.clone());
+                        continue;
+                    },
+                };
+
+                let (gpu, buffers) = match self.gpu_shape(shape) {
+                    Some(
+                        gpu @ GpuShape {
+                            buffers: Some(buffers),
+                            ..
+                        },
+                    ) => (gpu, buffers),
+                    _ => continue,
+                };
 
-            rpass.draw_indexed(0..indice_count as u32, 0, 0..1);
+                // Draw the shape's own batches with this batch's
+                // instances, then switch back to the frame's
+                // buffers.
+                rpass.set_bind_group(2, &buffers.transforms_bind_group, &[]);
+                rpass.set_index_buffer(buffers.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                rpass.set_vertex_buffer(0, buffers.vertex_buffer.slice(..));
+
+                for inner in gpu.batches.iter() {
+                    let bind_group = self.image_bind_group(inner.image.as_ref());
+                    rpass.set_bind_group(1, bind_group, &[]);
+                    rpass.draw_indexed(inner.indices.clone(), 0, batch.instances.clone());
+                }
+
+                rpass.set_bind_group(2, &self.transforms_bind_group, &[]);
+                rpass.set_index_buffer(self.index_buffer.slice(), wgpu::IndexFormat::Uint32);
+                rpass.set_vertex_buffer(0, self.vertex_buffer.slice());
+            }
+        }
+
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    /// Copy the resolved contents of `target` into its
+    /// multisampled texture.
+    fn reload_multisampled(&self, encoder: &mut wgpu::CommandEncoder, target: &RenderTarget) {
+        let (pipeline, msaa_view) = match (&self.msaa_reload_pipeline, &target.msaa_view) {
+            (Some(pipeline), Some(msaa_view)) => (pipeline, msaa_view),
+            _ => return,
+        };
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Peach::ReloadBindGroup"),
+            layout: &self.texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&target.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(
+                        &self.samplers[&Sampling::pixelated()],
+                    ),
+                },
+            ],
+        });
+
+        let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view: msaa_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        rpass.set_pipeline(pipeline);
+        rpass.set_bind_group(0, &bind_group, &[]);
+        rpass.draw(0..3, 0..1);
+    }
+
+    /// Finish a frame, evicting cached shapes and retained
+    /// buffers which have gone unused for too long.
+    pub fn end_frame(&mut self) {
+        self.tessellation_cache.end_frame();
+
+        self.frame += 1;
+        let frame = self.frame;
+        self.shapes.retain(|_, styles| {
+            styles.retain(|gpu| {
+                gpu.data.strong_count() > 0 && frame - gpu.last_used <= MAX_SHAPE_AGE
+            });
+            !styles.is_empty()
+        });
+    }
+
+    /// Copy the canvas onto the window surface. Does
+    /// nothing when headless.
+    pub fn present(&mut self) -> Result<(), RenderError> {
+        let presenter = match &self.presenter {
+            Some(presenter) => presenter,
+            None => return Ok(()),
+        };
+
+        let output = presenter
+            .surface
+            .get_current_texture()
+            .map_err(RenderError::SurfaceTexture)?;
+
+        let view = output
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+
+        {
+            let mut rpass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: None,
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            rpass.set_pipeline(&presenter.pipeline);
+            rpass.set_bind_group(0, &presenter.canvas_bind_group, &[]);
+            rpass.draw(0..3, 0..1);
         }
 
         self.queue.submit(std::iter::once(encoder.finish()));
@@ -265,4 +1362,52 @@ impl Renderer {
 
         Ok(())
     }
+
+    pub fn read_pixels(&self) -> Result<Pixels, RenderError> {
+        self.canvas.read_pixels(&self.device, &self.queue)
+    }
+
+    /// Read back the contents of a canvas image. Canvases
+    /// which have never been rendered into are transparent.
+    pub fn read_offscreen_pixels(&mut self, image: &Image) -> Result<Pixels, RenderError> {
+        self.prepare_image(image, self.resolve_sampling(image));
+        self.target(Some(image.id()))
+            .read_pixels(&self.device, &self.queue)
+    }
+
+    pub fn write_pixels(&self, pixels: &Pixels) -> Result<(), RenderError> {
+        self.canvas.write_pixels(&self.queue, pixels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsupported_sample_counts_are_errors() {
+        assert_eq!(msaa_sample_count(1).unwrap(), 1);
+        assert_eq!(msaa_sample_count(4).unwrap(), 4);
+
+        for samples in [0, 2, 3, 8, 16] {
+            assert!(matches!(
+                msaa_sample_count(samples),
+                Err(RendererInitError::MsaaSamples(n)) if n == samples
+            ));
+        }
+    }
+
+    #[test]
+    fn scaled_sizes_fit_in_memory() {
+        assert_eq!(scaled_size(64, 32, 4).unwrap(), (256, 128));
+        assert_eq!(scaled_size(1, 1, 32768).unwrap(), (32768, 32768));
+
+        for scale in [1 << 25, 1 << 27, u32::MAX] {
+            assert!(matches!(
+                scaled_size(64, 64, scale),
+                Err(RenderError::ScaledSize(64, 64, s)) if s == scale
+            ));
+        }
+        assert!(scaled_size(2, 1, 32768).is_err());
+    }
 }
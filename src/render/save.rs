@@ -0,0 +1,133 @@
+//! Writing frames to disk
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use rgb::{ComponentBytes, RGBA8};
+use thiserror::Error;
+
+use super::pixels::Pixels;
+use super::renderer::RenderError;
+
+#[derive(Error, Debug)]
+pub enum SaveError {
+    #[error("Failed to read back the frame: {0}")]
+    Render(#[from] RenderError),
+    #[error("Failed to write file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to encode PNG: {0}")]
+    Png(#[from] png::EncodingError),
+    #[error("Failed to encode GIF: {0}")]
+    Gif(#[from] gif::EncodingError),
+    #[error("ffmpeg exited with {0}")]
+    Ffmpeg(std::process::ExitStatus),
+    #[error("Frames of {0}x{1} are too large for a GIF, which is limited to 65535x65535")]
+    GifSize(u32, u32),
+    #[error("Frame size changed from {0}x{1} to {2}x{3} during the recording")]
+    FrameSize(u32, u32, u32, u32),
+    #[error("ffmpeg's stdin is no longer open")]
+    FfmpegStdin,
+}
+
+#[derive(Error, Debug)]
+pub enum LoadError {
+    #[error("Failed to read file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to decode PNG: {0}")]
+    Png(#[from] png::DecodingError),
+    #[error("Unsupported PNG format: {0:?}")]
+    Unsupported(png::ColorType),
+}
+
+/// Replace the last run of `#` characters in `pattern` with
+/// `frame`, zero padded to the length of the run.
+///
+/// ```
+/// # use peach::render::frame_path;
+/// assert_eq!(
+///     frame_path("frame-####.png", 42).to_str(),
+///     Some("frame-0042.png")
+/// );
+/// assert_eq!(
+///     frame_path("frame-#.png", 1234).to_str(),
+///     Some("frame-1234.png")
+/// );
+/// assert_eq!(frame_path("still.png", 7).to_str(), Some("still.png"));
+/// ```
+pub fn frame_path(pattern: &str, frame: u64) -> PathBuf {
+    let end = match pattern.rfind('#') {
+        Some(i) => i + 1,
+        None => return PathBuf::from(pattern),
+    };
+    let start = pattern[..end].trim_end_matches('#').len();
+
+    let width = end - start;
+    let number = format!("{:0width$}", frame, width = width);
+
+    PathBuf::from(format!(
+        "{}{}{}",
+        &pattern[..start],
+        number,
+        &pattern[end..]
+    ))
+}
+
+pub(crate) fn write_png(pixels: &Pixels, path: &Path) -> Result<(), SaveError> {
+    let file = BufWriter::new(File::create(path)?);
+    encode_png(pixels, file)
+}
+
+pub(crate) fn encode_png<W>(pixels: &Pixels, writer: W) -> Result<(), SaveError>
+where
+    W: Write,
+{
+    let mut encoder = png::Encoder::new(writer, pixels.width(), pixels.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels.as_raw().as_bytes())?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+pub(crate) fn read_png(path: &Path) -> Result<Pixels, LoadError> {
+    let file = BufReader::new(File::open(path)?);
+
+    let mut decoder = png::Decoder::new(file);
+    // Expand palettes and low bit depths, and strip 16-bit
+    // channels down to 8 bits.
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let bytes = &buffer[..info.buffer_size()];
+
+    let data: Vec<RGBA8> = match info.color_type {
+        png::ColorType::Rgba => {
+            bytes
+                .chunks_exact(4)
+                .map(|c| RGBA8::new(c[0], c[1], c[2], c[3]))
+                .collect()
+        },
+        png::ColorType::Rgb => {
+            bytes
+                .chunks_exact(3)
+                .map(|c| RGBA8::new(c[0], c[1], c[2], 255))
+                .collect()
+        },
+        png::ColorType::GrayscaleAlpha => {
+            bytes
+                .chunks_exact(2)
+                .map(|c| RGBA8::new(c[0], c[0], c[0], c[1]))
+                .collect()
+        },
+        png::ColorType::Grayscale => bytes.iter().map(|&c| RGBA8::new(c, c, c, 255)).collect(),
+        color_type => return Err(LoadError::Unsupported(color_type)),
+    };
+
+    Ok(Pixels::from_raw(info.width, info.height, data))
+}
@@ -1,33 +1,113 @@
 struct Uniforms {
     normalize: mat4x4<f32>;
+    // Pixels of the target per unit of the canvas.
+    pixel_scale: f32;
 };
 
 [[group(0), binding(0)]]
 var<uniform> uniforms: Uniforms;
 
+[[group(1), binding(0)]]
+var image_texture: texture_2d<f32>;
+[[group(1), binding(1)]]
+var image_sampler: sampler;
+
+struct Transform {
+    // Columns of the linear part.
+    linear: vec4<f32>;
+    translation: vec4<f32>;
+};
+
+struct Transforms {
+    data: array<Transform>;
+};
+
+[[group(2), binding(0)]]
+var<storage, read> transforms: Transforms;
+
 struct VertexInput {
     [[location(0)]] position: vec2<f32>;
     [[location(1)]] color: vec4<f32>;
+    [[location(2)]] uv: vec2<f32>;
+    [[location(3)]] transform: u32;
+    [[location(7)]] normal: vec2<f32>;
+    [[location(8)]] stroke: vec2<f32>;
+};
+
+struct InstanceInput {
+    [[location(4)]] linear: vec4<f32>;
+    [[location(5)]] translation: vec4<f32>;
+    [[location(6)]] tint: vec4<f32>;
 };
 
 struct VertexOutput {
     [[location(0)]] color: vec4<f32>;
+    [[location(1)]] uv: vec2<f32>;
+    // Distance from the middle of an anti-aliased stroke
+    // and its half width, in pixels. The half width is zero
+    // for geometry which isn't feathered.
+    [[location(2)]] edge: vec2<f32>;
     [[builtin(position)]] position: vec4<f32>;
 };
 
 [[stage(vertex)]]
 fn vs_main(
     input: VertexInput,
+    instance: InstanceInput,
 ) -> VertexOutput {
     var out: VertexOutput;
 
-    out.color = input.color;
-    out.position = uniforms.normalize * vec4<f32>(input.position, 0.0, 1.0);
+    out.color = input.color * instance.tint;
+    out.uv = input.uv;
+
+    let transform = transforms.data[input.transform];
+    let local = transform.linear.xy * input.position.x
+        + transform.linear.zw * input.position.y
+        + transform.translation.xy;
+    var position = instance.linear.xy * local.x
+        + instance.linear.zw * local.y
+        + instance.translation.xy;
+
+    out.edge = vec2<f32>(0.0, 0.0);
+    if (input.stroke.x != 0.0) {
+        // Extrude anti-aliased strokes here, where the size
+        // of a pixel is known.
+        let local_normal = transform.linear.xy * input.normal.x
+            + transform.linear.zw * input.normal.y;
+        let normal = (instance.linear.xy * local_normal.x
+            + instance.linear.zw * local_normal.y) * uniforms.pixel_scale;
+        // Longer than one at mitered joins.
+        let miter = length(input.normal);
+
+        var half_width = 0.5;
+        if (input.stroke.y >= 0.0) {
+            half_width = input.stroke.y * length(normal) / miter;
+        }
+
+        // Strokes are at least a pixel wide, with another
+        // half pixel either side to feather into.
+        let extent = max(half_width, 0.5) + 0.5;
+        position = position
+            + normalize(normal) * miter * extent / uniforms.pixel_scale;
+        out.edge = vec2<f32>(input.stroke.x * extent, half_width);
+    }
+
+    out.position = uniforms.normalize * vec4<f32>(position, 0.0, 1.0);
 
     return out;
 }
 
 [[stage(fragment)]]
 fn fs_main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    return in.color;
+    var color = in.color * textureSample(image_texture, image_sampler, in.uv);
+
+    let half_width = in.edge.y;
+    if (half_width > 0.0) {
+        // Strokes thinner than a pixel are drawn one pixel
+        // wide, and faded to match their weight.
+        let coverage = clamp(max(half_width, 0.5) + 0.5 - abs(in.edge.x), 0.0, 1.0);
+        color.a = color.a * coverage * min(2.0 * half_width, 1.0);
+    }
+
+    return color;
 }
@@ -0,0 +1,335 @@
+//! Shapes recorded once and drawn many times
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use super::context::Context;
+use super::graphics::{DrawCommand, Graphics};
+use crate::tess;
+use crate::tess::path::iterator::PathIterator;
+use crate::tess::path::PathEvent;
+use crate::types::{Color, GpuScalar, Point, RawInstance, RawTransform, Scalar, Transform, Vector};
+
+pub(crate) type ShapeId = u64;
+
+static NEXT_SHAPE_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) struct ShapeData {
+    id: ShapeId,
+    commands: Vec<DrawCommand>,
+}
+
+/// Overrides for the style a [`Shape`] was recorded with.
+/// Fields left as `None` keep the shape's own style.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShapeStyle {
+    /// Replaces every fill color of the shape, or removes
+    /// fills entirely with `Some(None)`.
+    pub fill: Option<Option<Color>>,
+    /// Replaces every stroke color of the shape, or removes
+    /// strokes entirely with `Some(None)`.
+    pub stroke: Option<Option<Color>>,
+    pub stroke_weight: Option<f32>,
+}
+
+impl ShapeStyle {
+    fn apply(&self, context: Context) -> Context {
+        Context {
+            fill: self.fill.unwrap_or(context.fill),
+            stroke: self.stroke.unwrap_or(context.stroke),
+            stroke_weight: self.stroke_weight.unwrap_or(context.stroke_weight),
+            ..context
+        }
+    }
+}
+
+/// Geometry recorded once with the usual [`Graphics`]
+/// calls, similar to Processing's `PShape`. Draw it with
+/// [`Graphics::shape`], or many copies at once with
+/// [`Graphics::instanced`].
+///
+/// The GPU renderer tessellates each shape once and keeps
+/// the result in its own buffers, so drawing it again only
+/// costs a draw call, however complex it is. The buffers
+/// are freed once every clone of the shape is dropped.
+///
+/// Recording starts from the default style, with a black
+/// fill and stroke, centered anchors and no transform.
+/// Shapes are cheap to clone, and clones share the same
+/// commands. The style can be overridden per handle, as
+/// with [`Shape::with_fill`]. Each distinct override is
+/// tessellated separately, so prefer an instance's tint
+/// for colors which change every frame.
+///
+/// ```
+/// # use peach::prelude::*;
+/// # use peach::render::Shape;
+/// let dot = Shape::new(|gfx| {
+///     gfx.fill(colors::WHITE);
+///     gfx.no_stroke();
+///     gfx.square([0.0, 0.0], 4.0);
+/// });
+/// ```
+#[derive(Clone)]
+pub struct Shape {
+    data: Arc<ShapeData>,
+    style: ShapeStyle,
+}
+
+impl Shape {
+    /// Record a shape with `draw`.
+    pub fn new<F>(draw: F) -> Self
+    where
+        F: FnOnce(&mut Graphics),
+    {
+        let mut gfx = Graphics::default();
+        draw(&mut gfx);
+        Self::from_graphics(gfx)
+    }
+
+    /// Use the commands recorded by `gfx` as a shape. The
+    /// clear color is ignored.
+    pub fn from_graphics(gfx: Graphics) -> Self {
+        Self::from_commands(gfx.into_commands())
+    }
+
+    /// A shape outlining `path`, with curves flattened to
+    /// within `tolerance`. Each subpath is filled on its
+    /// own, so overlapping subpaths don't cut holes.
+    ///
+    /// The path is drawn with the default style, which can
+    /// be changed with [`Shape::with_style`].
+    pub fn from_path(path: &tess::path::Path, tolerance: f32) -> Self {
+        let mut commands = Vec::new();
+        let mut points = Vec::new();
+
+        for event in path.iter().flattened(tolerance) {
+            match event {
+                PathEvent::Begin { at } => points.push(at.cast::<Scalar>()),
+                PathEvent::Line { to, .. } => points.push(to.cast::<Scalar>()),
+                PathEvent::End { close, .. } => {
+                    commands.push(DrawCommand::Draw(close, std::mem::take(&mut points)));
+                },
+                // Curves have been flattened into lines.
+                PathEvent::Quadratic { .. } | PathEvent::Cubic { .. } => {},
+            }
+        }
+
+        Self::from_commands(commands)
+    }
+
+    fn from_commands(commands: Vec<DrawCommand>) -> Self {
+        Self {
+            data: Arc::new(ShapeData {
+                id: NEXT_SHAPE_ID.fetch_add(1, Ordering::Relaxed),
+                commands,
+            }),
+            style: ShapeStyle::default(),
+        }
+    }
+
+    /// Overrides of the recorded style for this handle.
+    pub fn style(&self) -> ShapeStyle {
+        self.style
+    }
+
+    pub fn set_style(&mut self, style: ShapeStyle) {
+        self.style = style;
+    }
+
+    pub fn with_style(mut self, style: ShapeStyle) -> Self {
+        self.set_style(style);
+        self
+    }
+
+    pub fn with_fill<C>(mut self, color: C) -> Self
+    where
+        C: Into<Color>,
+    {
+        self.style.fill = Some(Some(color.into()));
+        self
+    }
+
+    pub fn with_no_fill(mut self) -> Self {
+        self.style.fill = Some(None);
+        self
+    }
+
+    pub fn with_stroke<C>(mut self, color: C) -> Self
+    where
+        C: Into<Color>,
+    {
+        self.style.stroke = Some(Some(color.into()));
+        self
+    }
+
+    pub fn with_no_stroke(mut self) -> Self {
+        self.style.stroke = Some(None);
+        self
+    }
+
+    pub fn with_stroke_weight(mut self, weight: f32) -> Self {
+        self.style.stroke_weight = Some(weight);
+        self
+    }
+
+    pub(crate) fn id(&self) -> ShapeId {
+        self.data.id
+    }
+
+    pub(crate) fn data(&self) -> &Arc<ShapeData> {
+        &self.data
+    }
+
+    /// The shape's commands with its style applied,
+    /// starting with the context they are drawn from,
+    /// and with any instanced draws inside it
+    /// flattened.
+    pub(super) fn styled_commands(&self) -> Vec<DrawCommand> {
+        let mut commands = vec![DrawCommand::UpdateContext(
+            self.style.apply(Context::default()),
+        )];
+
+        commands.extend(flatten(&self.data.commands).into_iter().map(|command| {
+            match command {
+                DrawCommand::UpdateContext(context) => {
+                    DrawCommand::UpdateContext(self.style.apply(context))
+                },
+                command => command,
+            }
+        }));
+
+        commands
+    }
+}
+
+/// One copy of a [`Shape`] drawn by
+/// [`Graphics::instanced`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instance {
+    /// Applied to the shape before the current transform.
+    pub transform: Transform,
+    /// Multiplied with every color of the shape, including
+    /// images. Drawing the shape in white makes the tint
+    /// its exact color.
+    pub tint: Color,
+}
+
+impl Instance {
+    /// An instance moved to `position`.
+    pub fn at<P>(position: P) -> Self
+    where
+        P: Into<Point>,
+    {
+        let position = position.into();
+        Self {
+            transform: Transform::translation(position.x, position.y),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_tint<C>(mut self, tint: C) -> Self
+    where
+        C: Into<Color>,
+    {
+        self.tint = tint.into();
+        self
+    }
+
+    /// Scale the instance uniformly, about the shape's
+    /// origin.
+    pub fn with_scale(mut self, scale: Scalar) -> Self {
+        self.transform = Transform::scale(scale, scale).then(&self.transform);
+        self
+    }
+
+    /// Move the instance by `by`, after its other
+    /// transforms.
+    pub fn with_translation<V>(mut self, by: V) -> Self
+    where
+        V: Into<Vector>,
+    {
+        self.transform = self.transform.then_translate(by.into());
+        self
+    }
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            transform: Transform::identity(),
+            tint: Color::new(1.0, 1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl From<Instance> for RawInstance {
+    fn from(instance: Instance) -> Self {
+        let tint = instance.tint;
+
+        Self {
+            transform: RawTransform::from(instance.transform),
+            tint: [tint.r, tint.g, tint.b, tint.a].map(|c| c as GpuScalar),
+        }
+    }
+}
+
+pub(super) fn tinted(color: Color, tint: Color) -> Color {
+    Color::new(
+        color.r * tint.r,
+        color.g * tint.g,
+        color.b * tint.b,
+        color.a * tint.a,
+    )
+}
+
+/// The context a shape command is drawn with, for an
+/// instance drawn under `transform`.
+fn instance_context(context: Context, transform: &Transform, tint: Color) -> Context {
+    Context {
+        transform: context.transform.then(transform),
+        fill: context.fill.map(|color| tinted(color, tint)),
+        stroke: context.stroke.map(|color| tinted(color, tint)),
+        tint: tinted(context.tint, tint),
+        ..context
+    }
+}
+
+/// Expand every instanced draw in `commands` into a copy of
+/// its shape per instance, for backends and exporters which
+/// don't draw instances themselves.
+pub(super) fn flatten(commands: &[DrawCommand]) -> Vec<DrawCommand> {
+    let mut output = Vec::with_capacity(commands.len());
+    let mut current_context = Context::default();
+
+    for command in commands {
+        match command {
+            DrawCommand::Instanced(shape, instances) => {
+                let shape_commands = shape.styled_commands();
+
+                for instance in instances.iter() {
+                    let transform = instance.transform.then(&current_context.transform);
+                    let context = |context| instance_context(context, &transform, instance.tint);
+
+                    for command in shape_commands.iter() {
+                        output.push(match command {
+                            DrawCommand::UpdateContext(shape_context) => {
+                                DrawCommand::UpdateContext(context(*shape_context))
+                            },
+                            command => command.clone(),
+                        });
+                    }
+                }
+
+                output.push(DrawCommand::UpdateContext(current_context));
+            },
+            DrawCommand::UpdateContext(context) => {
+                current_context = *context;
+                output.push(command.clone());
+            },
+            command => output.push(command.clone()),
+        }
+    }
+
+    output
+}
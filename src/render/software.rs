@@ -0,0 +1,583 @@
+//! CPU rasterizer for machines without a GPU
+//!
+//! Renders the same [`BufferData`] as the GPU renderer,
+//! following the same rules: pixels are sampled at their
+//! centers with a top-left fill rule, blending happens in
+//! linear space on an sRGB encoded canvas, and images are
+//! sampled with the same filtering and wrapping options.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+
+use rgb::RGBA8;
+use winit::dpi::PhysicalSize;
+
+use super::cache::{TessellationCache, TessellationStats};
+use super::construct::Batch;
+use super::graphics::{BufferData, Graphics};
+use super::image::{self, FilterMode, Image, ImageData, ImageId, Sampling, WrapMode};
+use super::pixels::{encode_color, Pixels};
+use super::renderer::{scaled_size, RenderError};
+use crate::types::{Color, GpuScalar, RawVertex, Scalar};
+
+type Linear = [f32; 4];
+
+fn decode_table() -> Box<[f32; 256]> {
+    let mut table = Box::new([0.0; 256]);
+    for (i, value) in table.iter_mut().enumerate() {
+        let c = i as f32 / 255.0;
+        *value = if c <= 0.040_45 {
+            c / 12.92
+        }
+        else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        };
+    }
+    table
+}
+
+fn encode(color: Linear) -> RGBA8 {
+    let [r, g, b, a] = color;
+    encode_color(Color::new(
+        r as Scalar,
+        g as Scalar,
+        b as Scalar,
+        a as Scalar,
+    ))
+}
+
+struct Level {
+    width: u32,
+    height: u32,
+    texels: Vec<Linear>,
+}
+
+/// An image decoded into linear space, along with its mip
+/// levels when they are needed.
+struct Texture {
+    levels: Vec<Level>,
+}
+
+impl Texture {
+    fn new(decode: &[f32; 256], width: u32, height: u32, pixels: &[RGBA8], mipmaps: bool) -> Self {
+        let to_level = |width, height, pixels: &[RGBA8]| {
+            Level {
+                width,
+                height,
+                texels: pixels
+                    .iter()
+                    .map(|p| {
+                        [
+                            decode[p.r as usize],
+                            decode[p.g as usize],
+                            decode[p.b as usize],
+                            p.a as f32 / 255.0,
+                        ]
+                    })
+                    .collect(),
+            }
+        };
+
+        let mut levels = vec![to_level(width, height, pixels)];
+        if mipmaps {
+            for (width, height, pixels) in image::generate_mips(width, height, pixels) {
+                levels.push(to_level(width, height, &pixels));
+            }
+        }
+
+        Self { levels }
+    }
+
+    fn texel(level: &Level, x: i64, y: i64, wrap: WrapMode) -> Linear {
+        let x = wrap_coordinate(x, level.width as i64, wrap);
+        let y = wrap_coordinate(y, level.height as i64, wrap);
+        level.texels[(y * level.width as i64 + x) as usize]
+    }
+
+    fn sample(&self, sampling: Sampling, u: f64, v: f64, lod: f64) -> Linear {
+        let level = if sampling.mipmaps {
+            (lod.max(0.0).round() as usize).min(self.levels.len() - 1)
+        }
+        else {
+            0
+        };
+        let level = &self.levels[level];
+
+        let x = u * level.width as f64;
+        let y = v * level.height as f64;
+
+        match sampling.filter {
+            FilterMode::Nearest => {
+                Self::texel(level, x.floor() as i64, y.floor() as i64, sampling.wrap)
+            },
+            FilterMode::Linear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = ((x - x0) as f32, (y - y0) as f32);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+
+                let a = Self::texel(level, x0, y0, sampling.wrap);
+                let b = Self::texel(level, x0 + 1, y0, sampling.wrap);
+                let c = Self::texel(level, x0, y0 + 1, sampling.wrap);
+                let d = Self::texel(level, x0 + 1, y0 + 1, sampling.wrap);
+
+                let mut out = [0.0; 4];
+                for i in 0..4 {
+                    let top = a[i] + (b[i] - a[i]) * fx;
+                    let bottom = c[i] + (d[i] - c[i]) * fx;
+                    out[i] = top + (bottom - top) * fy;
+                }
+                out
+            },
+        }
+    }
+}
+
+fn wrap_coordinate(i: i64, n: i64, wrap: WrapMode) -> i64 {
+    match wrap {
+        WrapMode::Clamp => i.clamp(0, n - 1),
+        WrapMode::Repeat => i.rem_euclid(n),
+        WrapMode::Mirror => {
+            let m = i.rem_euclid(2 * n);
+            if m < n {
+                m
+            }
+            else {
+                2 * n - 1 - m
+            }
+        },
+    }
+}
+
+/// A decoded image, along with the version of its pixels
+/// it was decoded from.
+struct CachedTexture {
+    data: Weak<ImageData>,
+    version: u64,
+    texture: Arc<Texture>,
+}
+
+/// An sRGB encoded RGBA buffer which triangles are
+/// rasterized into.
+struct SoftwareTarget {
+    width: u32,
+    height: u32,
+    pixels: Vec<RGBA8>,
+    // Incremented every time the target is drawn into.
+    version: u64,
+}
+
+impl SoftwareTarget {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![RGBA8::new(0, 0, 0, 0); width as usize * height as usize],
+            version: 0,
+        }
+    }
+
+    fn clear(&mut self, color: Color) {
+        let pixel = encode_color(color);
+        self.pixels.iter_mut().for_each(|p| *p = pixel);
+    }
+
+    /// Blend `src` over the pixel at `index`, matching
+    /// `wgpu::BlendState::ALPHA_BLENDING`.
+    fn blend(&mut self, decode: &[f32; 256], index: usize, src: Linear) {
+        let dst = self.pixels[index];
+        let dst = [
+            decode[dst.r as usize],
+            decode[dst.g as usize],
+            decode[dst.b as usize],
+            dst.a as f32 / 255.0,
+        ];
+
+        let a = src[3];
+        let out = [
+            src[0] * a + dst[0] * (1.0 - a),
+            src[1] * a + dst[1] * (1.0 - a),
+            src[2] * a + dst[2] * (1.0 - a),
+            a + dst[3] * (1.0 - a),
+        ];
+
+        self.pixels[index] = encode(out);
+    }
+
+    fn draw(
+        &mut self,
+        decode: &[f32; 256],
+        data: &BufferData,
+        textures: &[(Arc<Texture>, Sampling)],
+        clear_color: Option<Color>,
+    ) {
+        self.version += 1;
+
+        if let Some(color) = clear_color {
+            self.clear(color);
+        }
+
+        let BufferData {
+            vertices,
+            indices,
+            batches,
+            ..
+        } = data;
+
+        for (batch, (texture, sampling)) in batches.iter().zip(textures) {
+            let indices = &indices[batch.indices.start as usize..batch.indices.end as usize];
+
+            for triangle in indices.chunks_exact(3) {
+                let triangle = [
+                    &vertices[triangle[0] as usize],
+                    &vertices[triangle[1] as usize],
+                    &vertices[triangle[2] as usize],
+                ];
+                self.rasterize(decode, triangle, texture, *sampling);
+            }
+        }
+    }
+
+    fn rasterize(
+        &mut self,
+        decode: &[f32; 256],
+        vertices: [&RawVertex; 3],
+        texture: &Texture,
+        sampling: Sampling,
+    ) {
+        let position = |v: &RawVertex| (v.position[0] as f64, v.position[1] as f64);
+
+        let [a, mut b, mut c] = vertices;
+        let (ax, ay) = position(a);
+        let (bx, by) = position(b);
+        let (cx, cy) = position(c);
+
+        let mut area = (bx - ax) * (cy - ay) - (by - ay) * (cx - ax);
+        if area == 0.0 {
+            return;
+        }
+        // Rasterize every triangle with the same winding, so the
+        // edge functions are positive inside.
+        if area < 0.0 {
+            std::mem::swap(&mut b, &mut c);
+            area = -area;
+        }
+
+        let (ax, ay) = position(a);
+        let (bx, by) = position(b);
+        let (cx, cy) = position(c);
+
+        let edge = |(x0, y0): (f64, f64), (x1, y1): (f64, f64), px: f64, py: f64| {
+            (x1 - x0) * (py - y0) - (y1 - y0) * (px - x0)
+        };
+        // Pixels exactly on an edge belong to the triangle only
+        // when the edge is a top or left edge.
+        let top_left = |(x0, y0): (f64, f64), (x1, y1): (f64, f64)| {
+            let (dx, dy) = (x1 - x0, y1 - y0);
+            dy < 0.0 || (dy == 0.0 && dx > 0.0)
+        };
+        let inside = |w: f64, top_left: bool| w > 0.0 || (w == 0.0 && top_left);
+
+        let tl_bc = top_left((bx, by), (cx, cy));
+        let tl_ca = top_left((cx, cy), (ax, ay));
+        let tl_ab = top_left((ax, ay), (bx, by));
+
+        // Texture coordinates are affine in screen space, so the
+        // mip level is constant across the triangle.
+        let level = texture.levels[0].width.max(texture.levels[0].height) as f64;
+        let lod = if sampling.mipmaps && texture.levels.len() > 1 {
+            let uv = |v: &RawVertex| (v.uv[0] as f64, v.uv[1] as f64);
+            let ((au, av), (bu, bv), (cu, cv)) = (uv(a), uv(b), uv(c));
+
+            let du_dx = (au * (by - cy) + bu * (cy - ay) + cu * (ay - by)) / area;
+            let dv_dx = (av * (by - cy) + bv * (cy - ay) + cv * (ay - by)) / area;
+            let du_dy = (au * (cx - bx) + bu * (ax - cx) + cu * (bx - ax)) / area;
+            let dv_dy = (av * (cx - bx) + bv * (ax - cx) + cv * (bx - ax)) / area;
+
+            let dx = (du_dx * du_dx + dv_dx * dv_dx).sqrt() * level;
+            let dy = (du_dy * du_dy + dv_dy * dv_dy).sqrt() * level;
+            dx.max(dy).max(f64::MIN_POSITIVE).log2()
+        }
+        else {
+            0.0
+        };
+
+        let min_x = ax.min(bx).min(cx).floor().max(0.0) as u32;
+        let min_y = ay.min(by).min(cy).floor().max(0.0) as u32;
+        let max_x = (ax.max(bx).max(cx).ceil().max(0.0) as u32).min(self.width);
+        let max_y = (ay.max(by).max(cy).ceil().max(0.0) as u32).min(self.height);
+
+        for y in min_y..max_y {
+            let py = y as f64 + 0.5;
+
+            for x in min_x..max_x {
+                let px = x as f64 + 0.5;
+
+                let wa = edge((bx, by), (cx, cy), px, py);
+                let wb = edge((cx, cy), (ax, ay), px, py);
+                let wc = edge((ax, ay), (bx, by), px, py);
+
+                if !(inside(wa, tl_bc) && inside(wb, tl_ca) && inside(wc, tl_ab)) {
+                    continue;
+                }
+
+                let (la, lb, lc) = ((wa / area) as f32, (wb / area) as f32, (wc / area) as f32);
+                let lerp = |i: usize, f: fn(&RawVertex) -> &[f32]| {
+                    f(a)[i] * la + f(b)[i] * lb + f(c)[i] * lc
+                };
+
+                let u = lerp(0, |v| &v.uv) as f64;
+                let v = lerp(1, |v| &v.uv) as f64;
+                let texel = texture.sample(sampling, u, v, lod);
+
+                let mut color = [0.0; 4];
+                for (i, channel) in color.iter_mut().enumerate() {
+                    *channel = lerp(i, |v| &v.color) * texel[i];
+                }
+
+                let index = (y * self.width + x) as usize;
+                self.blend(decode, index, color);
+            }
+        }
+    }
+}
+
+/// Renders [`Graphics`] on the CPU, with the same interface
+/// as the GPU [`Renderer`](super::renderer::Renderer).
+pub(crate) struct Rasterizer {
+    canvas: SoftwareTarget,
+    targets: HashMap<ImageId, (Weak<ImageData>, SoftwareTarget)>,
+    // Decoded images, rebuilt only when their pixels change.
+    textures: HashMap<ImageId, CachedTexture>,
+    // Used for untextured geometry.
+    white: Arc<Texture>,
+
+    default_sampling: Sampling,
+    decode: Box<[f32; 256]>,
+    tessellation_cache: Box<TessellationCache>,
+}
+
+impl Rasterizer {
+    pub fn new(size: PhysicalSize<u32>, default_sampling: Sampling) -> Self {
+        let decode = decode_table();
+        let white = [RGBA8::new(255, 255, 255, 255)];
+        let white = Arc::new(Texture::new(&decode, 1, 1, &white, false));
+
+        Self {
+            canvas: SoftwareTarget::new(size.width, size.height),
+            targets: HashMap::new(),
+            textures: HashMap::new(),
+            white,
+            default_sampling,
+            decode,
+            tessellation_cache: Box::default(),
+        }
+    }
+
+    pub fn size(&self) -> PhysicalSize<u32> {
+        PhysicalSize::new(self.canvas.width, self.canvas.height)
+    }
+
+    pub fn tessellation_stats(&self) -> TessellationStats {
+        self.tessellation_cache.stats()
+    }
+
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        if size.width > 0 && size.height > 0 {
+            self.canvas = SoftwareTarget::new(size.width, size.height);
+        }
+    }
+
+    pub fn draw(&mut self, gfx: Graphics) -> Result<(), RenderError> {
+        self.draw_to(None, gfx)
+    }
+
+    /// Finish a frame, evicting cached shapes which have
+    /// gone unused for too long.
+    pub fn end_frame(&mut self) {
+        self.tessellation_cache.end_frame();
+    }
+
+    pub fn draw_offscreen(&mut self, image: &Image, gfx: Graphics) -> Result<(), RenderError> {
+        self.prepare_target(image);
+        self.draw_to(Some(image.id()), gfx)
+    }
+
+    fn prepare_target(&mut self, image: &Image) {
+        let data = image.data();
+        self.targets.entry(data.id).or_insert_with(|| {
+            let target = SoftwareTarget::new(data.width, data.height);
+            (Arc::downgrade(data), target)
+        });
+    }
+
+    /// The decoded texture of `image`, which is only
+    /// decoded again once its pixels change.
+    fn texture(&mut self, image: Option<&Image>, mipmaps: bool) -> Arc<Texture> {
+        let image = match image {
+            Some(image) => image,
+            None => return self.white.clone(),
+        };
+
+        let data = image.data();
+        let contents = data.lock();
+        let target = self.targets.get(&data.id).map(|(_, target)| target);
+        let version = match (&contents, target) {
+            (Some(contents), _) => contents.version(),
+            (None, Some(target)) => target.version,
+            (None, None) => 0,
+        };
+
+        if let Some(cached) = self.textures.get(&data.id) {
+            let levels = image::mip_level_count(data.width, data.height) as usize;
+            if cached.version == version && (!mipmaps || cached.texture.levels.len() == levels) {
+                return cached.texture.clone();
+            }
+        }
+
+        let texture = match (&contents, target) {
+            (Some(contents), _) => {
+                Texture::new(
+                    &self.decode,
+                    data.width,
+                    data.height,
+                    &contents.pixels,
+                    mipmaps,
+                )
+            },
+            (None, Some(target)) => {
+                Texture::new(
+                    &self.decode,
+                    data.width,
+                    data.height,
+                    &target.pixels,
+                    mipmaps,
+                )
+            },
+            (None, None) => {
+                // Never rendered into, so still transparent.
+                let pixels = vec![RGBA8::new(0, 0, 0, 0); (data.width * data.height) as usize];
+                Texture::new(&self.decode, data.width, data.height, &pixels, mipmaps)
+            },
+        };
+
+        let texture = Arc::new(texture);
+        self.textures.insert(
+            data.id,
+            CachedTexture {
+                data: Arc::downgrade(data),
+                version,
+                texture: texture.clone(),
+            },
+        );
+
+        texture
+    }
+
+    fn draw_to(&mut self, target_id: Option<ImageId>, gfx: Graphics) -> Result<(), RenderError> {
+        let clear_color = gfx.clear_color;
+        let data = self.prepare(gfx, target_id)?;
+        let textures = self.textures(&data.batches);
+
+        let decode = &self.decode;
+        let target = match target_id {
+            None => &mut self.canvas,
+            Some(id) => &mut self.targets.get_mut(&id).unwrap().1,
+        };
+
+        target.draw(decode, &data, &textures, clear_color);
+
+        Ok(())
+    }
+
+    /// Render `gfx` at `scale` times the canvas size, and
+    /// return the result.
+    pub fn render_scaled(&mut self, gfx: Graphics, scale: u32) -> Result<Pixels, RenderError> {
+        let scale = scale.max(1);
+        let (width, height) = scaled_size(self.canvas.width, self.canvas.height, scale)?;
+        let clear_color = gfx.clear_color;
+
+        let mut data = self.prepare(gfx, None)?;
+        for vertex in data.vertices.iter_mut() {
+            vertex.position[0] *= scale as GpuScalar;
+            vertex.position[1] *= scale as GpuScalar;
+        }
+        let textures = self.textures(&data.batches);
+
+        let mut target = SoftwareTarget::new(width, height);
+        target.draw(&self.decode, &data, &textures, clear_color);
+
+        Ok(Pixels::from_raw(target.width, target.height, target.pixels))
+    }
+
+    fn prepare(
+        &mut self,
+        gfx: Graphics,
+        target_id: Option<ImageId>,
+    ) -> Result<BufferData, RenderError> {
+        let mut data = gfx
+            .construct_buffer_data(&mut self.tessellation_cache, false)
+            .map_err(RenderError::BufferConstruct)?;
+        data.apply_transforms();
+
+        self.targets.retain(|_, (data, _)| data.strong_count() > 0);
+        self.textures
+            .retain(|_, cached| cached.data.strong_count() > 0);
+
+        if data
+            .batches
+            .iter()
+            .any(|batch| batch.image.as_ref().map(Image::id) == target_id && target_id.is_some())
+        {
+            return Err(RenderError::CanvasFeedback);
+        }
+
+        Ok(data)
+    }
+
+    /// Build every texture up front, so the target can be
+    /// borrowed mutably while rasterizing.
+    fn textures(&mut self, batches: &[Batch]) -> Vec<(Arc<Texture>, Sampling)> {
+        batches
+            .iter()
+            .map(|Batch { image, .. }| {
+                let sampling = image
+                    .as_ref()
+                    .and_then(Image::sampling)
+                    .unwrap_or(self.default_sampling);
+                (self.texture(image.as_ref(), sampling.mipmaps), sampling)
+            })
+            .collect()
+    }
+
+    pub fn read_pixels(&self) -> Result<Pixels, RenderError> {
+        let SoftwareTarget {
+            width,
+            height,
+            pixels,
+            ..
+        } = &self.canvas;
+
+        Ok(Pixels::from_raw(*width, *height, pixels.clone()))
+    }
+
+    pub fn write_pixels(&mut self, pixels: &Pixels) -> Result<(), RenderError> {
+        if pixels.width() != self.canvas.width || pixels.height() != self.canvas.height {
+            return Err(RenderError::PixelsSize);
+        }
+
+        self.canvas.pixels.copy_from_slice(pixels.as_raw());
+        Ok(())
+    }
+
+    pub fn read_offscreen_pixels(&mut self, image: &Image) -> Result<Pixels, RenderError> {
+        self.prepare_target(image);
+
+        let (_, target) = &self.targets[&image.id()];
+        Ok(Pixels::from_raw(
+            target.width,
+            target.height,
+            target.pixels.clone(),
+        ))
+    }
+}
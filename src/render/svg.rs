@@ -0,0 +1,296 @@
+//! Exporting recorded draw commands as SVG
+
+use std::fmt::Write;
+
+use super::context::{Context, StrokeMode};
+use super::graphics::DrawCommand;
+use super::image::FilterMode;
+use super::pixels::encode_color;
+use super::save::encode_png;
+use crate::types::{Color, Scalar, Size};
+
+// Writing into a `String` can't fail, so the results of
+// `write!` are ignored throughout.
+
+pub(super) fn to_svg(commands: &[DrawCommand], clear_color: Option<Color>, size: Size) -> String {
+    let mut svg = String::new();
+
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = size.width,
+        h = size.height,
+    );
+
+    if let Some(color) = clear_color {
+        svg.push_str(r#"  <rect width="100%" height="100%""#);
+        write_paint(&mut svg, "fill", Some(color));
+        svg.push_str("/>\n");
+    }
+
+    // Every context gets its own group, so the style and
+    // transform are inherited by the shapes drawn with it.
+    let mut context = Context::default();
+    open_group(&mut svg, &context);
+    // Tinted images each get a filter with a unique id.
+    let mut filters = 0;
+
+    for command in commands {
+        match command {
+            DrawCommand::Draw(closed, points) => {
+                let element = if *closed { "polygon" } else { "polyline" };
+
+                let _ = write!(svg, r#"    <{} points=""#, element);
+                for (i, point) in points.iter().enumerate() {
+                    if i > 0 {
+                        svg.push(' ');
+                    }
+                    let _ = write!(svg, "{},{}", point.x, point.y);
+                }
+                svg.push('"');
+                // Unlike the other stroke attributes, this
+                // isn't inherited from the group.
+                if context.stroke_mode == StrokeMode::Hairline {
+                    svg.push_str(r#" vector-effect="non-scaling-stroke""#);
+                }
+                svg.push_str("/>\n");
+            },
+            DrawCommand::Image(image, rect, uv) => {
+                // Canvases only exist on the GPU, so there is
+                // nothing to embed.
+                let pixels = match image.to_pixels() {
+                    Some(pixels) => pixels,
+                    None => continue,
+                };
+
+                let mut png = Vec::new();
+                if encode_png(&pixels, &mut png).is_err() {
+                    continue;
+                }
+
+                // A nested viewport crops the image to `uv`.
+                let width = pixels.width() as Scalar;
+                let height = pixels.height() as Scalar;
+                let _ = writeln!(
+                    svg,
+                    r#"    <svg x="{}" y="{}" width="{}" height="{}" viewBox="{} {} {} {}" preserveAspectRatio="none">"#,
+                    rect.min.x,
+                    rect.min.y,
+                    rect.width(),
+                    rect.height(),
+                    uv.min.x * width,
+                    uv.min.y * height,
+                    uv.width() * width,
+                    uv.height() * height,
+                );
+
+                // Tints multiply linear colors, which is what
+                // filters work in by default.
+                let mut filter = String::new();
+                let tint = context.tint;
+                if tint != Color::new(1.0, 1.0, 1.0, 1.0) {
+                    filters += 1;
+                    let _ = writeln!(
+                        svg,
+                        r#"      <filter id="tint-{}" color-interpolation-filters="linearRGB"><feColorMatrix type="matrix" values="{} 0 0 0 0 0 {} 0 0 0 0 0 {} 0 0 0 0 0 {} 0"/></filter>"#,
+                        filters, tint.r, tint.g, tint.b, tint.a,
+                    );
+                    filter = format!(r#" filter="url(#tint-{})""#, filters);
+                }
+
+                let pixelated = matches!(
+                    image.sampling().map(|sampling| sampling.filter),
+                    Some(FilterMode::Nearest)
+                );
+                let _ = writeln!(
+                    svg,
+                    r#"      <image width="{}" height="{}"{}{} href="data:image/png;base64,{}"/>"#,
+                    width,
+                    height,
+                    filter,
+                    if pixelated {
+                        r#" style="image-rendering:pixelated""#
+                    }
+                    else {
+                        ""
+                    },
+                    base64(&png),
+                );
+                svg.push_str("    </svg>\n");
+            },
+            DrawCommand::UpdateContext(new_context) => {
+                context = *new_context;
+
+                svg.push_str("  </g>\n");
+                open_group(&mut svg, &context);
+            },
+            DrawCommand::Instanced(..) => unreachable!("instanced draws are flattened first"),
+        }
+    }
+
+    svg.push_str("  </g>\n</svg>\n");
+    svg
+}
+
+fn open_group(svg: &mut String, context: &Context) {
+    let t = &context.transform;
+    let stroke_weight = match context.stroke_mode {
+        StrokeMode::Hairline => 1.0,
+        _ => context.stroke_weight,
+    };
+
+    // lyon fills with the even-odd rule by default.
+    let _ = write!(
+        svg,
+        r#"  <g transform="matrix({} {} {} {} {} {})" fill-rule="evenodd" stroke-width="{}""#,
+        t.m11, t.m12, t.m21, t.m22, t.m31, t.m32, stroke_weight,
+    );
+    write_paint(svg, "fill", context.fill);
+    write_paint(svg, "stroke", context.stroke);
+    svg.push_str(">\n");
+}
+
+/// Write `color` as an sRGB hex color, with a separate
+/// opacity attribute when it is translucent.
+fn write_paint(svg: &mut String, attribute: &str, color: Option<Color>) {
+    let color = match color {
+        Some(color) => color,
+        None => {
+            let _ = write!(svg, r#" {}="none""#, attribute);
+            return;
+        },
+    };
+
+    let pixel = encode_color(color);
+    let _ = write!(
+        svg,
+        r##" {}="#{:02x}{:02x}{:02x}""##,
+        attribute, pixel.r, pixel.g, pixel.b
+    );
+
+    let alpha = color.a.clamp(0.0, 1.0);
+    if alpha < 1.0 {
+        let _ = write!(svg, r#" {}-opacity="{}""#, attribute, alpha);
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len() * 4 / 3 + 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            }
+            else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::render::{Graphics, Image, Instance, Shape, StrokeMode};
+    use crate::types::{Angle, Color, Scalar};
+
+    /// The numbers in the transform of the last group.
+    fn last_matrix(svg: &str) -> Vec<Scalar> {
+        let start = svg.rfind("matrix(").unwrap() + "matrix(".len();
+        let end = start + svg[start..].find(')').unwrap();
+        svg[start..end]
+            .split(' ')
+            .map(|n| n.parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn fills_and_strokes_are_attributes() {
+        let mut gfx = Graphics::default();
+        gfx.fill(Color::new(1.0, 0.0, 0.0, 0.5));
+        gfx.stroke(Color::new(0.0, 0.0, 1.0, 1.0));
+        gfx.stroke_weight(3.0);
+        gfx.rect([10.0, 10.0], [4.0, 2.0]);
+
+        let svg = gfx.to_svg([20.0, 20.0]);
+        assert!(
+            svg.contains(r##"fill="#ff0000" fill-opacity="0.5""##),
+            "{}",
+            svg
+        );
+        assert!(svg.contains(r##"stroke="#0000ff""##), "{}", svg);
+        assert!(svg.contains(r#"stroke-width="3""#), "{}", svg);
+        assert!(svg.contains(r#"fill-rule="evenodd""#), "{}", svg);
+        assert!(
+            svg.contains(r#"<polygon points="8,9 12,9 12,11 8,11"/>"#),
+            "{}",
+            svg
+        );
+
+        gfx.no_fill();
+        gfx.rect([0.0, 0.0], [1.0, 1.0]);
+        assert!(gfx.to_svg([20.0, 20.0]).contains(r#"fill="none""#));
+    }
+
+    #[test]
+    fn transforms_are_applied_in_order() {
+        let mut gfx = Graphics::default();
+        gfx.rotate(Angle::degrees(90.0));
+        gfx.translate([10.0, 20.0]);
+        gfx.rect([0.0, 0.0], [1.0, 1.0]);
+
+        // The translation comes after the rotation, so it
+        // isn't rotated itself.
+        let matrix = last_matrix(&gfx.to_svg([20.0, 20.0]));
+        let expected = [0.0, 1.0, -1.0, 0.0, 10.0, 20.0];
+        for (value, expected) in matrix.iter().zip(expected.iter()) {
+            assert!((value - expected).abs() < 1e-6, "{:?}", matrix);
+        }
+    }
+
+    #[test]
+    fn hairlines_do_not_scale() {
+        let mut gfx = Graphics::default();
+        gfx.stroke_mode(StrokeMode::Hairline);
+        gfx.stroke_weight(5.0);
+        gfx.rect([0.0, 0.0], [1.0, 1.0]);
+
+        let svg = gfx.to_svg([20.0, 20.0]);
+        assert!(svg.contains(r#"stroke-width="1""#), "{}", svg);
+        assert!(
+            svg.contains(r#"vector-effect="non-scaling-stroke""#),
+            "{}",
+            svg
+        );
+    }
+
+    #[test]
+    fn tinted_images_are_filtered() {
+        let image = Image::new(2, 2);
+        let shape = Shape::new(|gfx| gfx.image(&image, [0.0, 0.0], [2.0, 2.0]));
+        let tint = Color::new(0.5, 0.25, 1.0, 1.0);
+
+        let mut gfx = Graphics::default();
+        gfx.image(&image, [0.0, 0.0], [2.0, 2.0]);
+        let svg = gfx.to_svg([20.0, 20.0]);
+        assert!(!svg.contains("filter"), "{}", svg);
+
+        gfx.instanced(&shape, &[Instance::at([5.0, 5.0]).with_tint(tint)]);
+        let svg = gfx.to_svg([20.0, 20.0]);
+        assert!(
+            svg.contains(r#"values="0.5 0 0 0 0 0 0.25 0 0 0 0 0 1 0 0 0 0 0 1 0""#),
+            "{}",
+            svg
+        );
+        assert_eq!(svg.matches(r#"filter="url(#tint-1)""#).count(), 1);
+    }
+}
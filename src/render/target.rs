@@ -0,0 +1,233 @@
+//! Textures which can be rendered into and read back
+
+use std::cell::Cell;
+use std::num::NonZeroU32;
+
+use rgb::{ComponentBytes, FromSlice};
+use wgpu::util::DeviceExt;
+
+use super::pixels::Pixels;
+use super::renderer::RenderError;
+use crate::types::{GpuScalar, Scalar, Transform, Vector};
+
+pub(super) const TARGET_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub(super) struct Uniforms {
+    normalize: [GpuScalar; 16],
+    pixel_scale: GpuScalar,
+    // Uniform structs are padded to 16 bytes.
+    _padding: [GpuScalar; 3],
+}
+
+unsafe impl bytemuck::Pod for Uniforms {}
+unsafe impl bytemuck::Zeroable for Uniforms {}
+
+impl Uniforms {
+    pub fn generate(width: u32, height: u32) -> Self {
+        Self::tile(0, 0, width, height, 1.0)
+    }
+
+    /// Uniforms for the `width` by `height` region at
+    /// `(x, y)` of a frame scaled up by `scale`.
+    pub fn tile(x: u32, y: u32, width: u32, height: u32, scale: Scalar) -> Self {
+        let width = width as Scalar;
+        let height = height as Scalar;
+
+        let transform = Transform::scale(scale, scale)
+            .then_translate(Vector::new(-(x as Scalar), -(y as Scalar)))
+            .then_scale(2.0 / width, -2.0 / height)
+            .then_translate(Vector::new(-1.0, 1.0));
+
+        Self {
+            normalize: transform.cast::<GpuScalar>().to_3d().to_array(),
+            pixel_scale: scale as GpuScalar,
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+/// An RGBA texture that geometry is rendered into, along
+/// with the uniforms which map pixel coordinates onto it.
+pub(super) struct RenderTarget {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub width: u32,
+    pub height: u32,
+
+    pub bind_group: wgpu::BindGroup,
+
+    // Multisampled texture which geometry is drawn into
+    // before being resolved into `texture`, when using
+    // MSAA.
+    pub msaa_view: Option<wgpu::TextureView>,
+    // Set when `texture` was changed directly, so the
+    // multisampled texture no longer matches it.
+    pub msaa_stale: Cell<bool>,
+}
+
+impl RenderTarget {
+    pub fn new(
+        device: &wgpu::Device,
+        uniforms_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+        samples: u32,
+    ) -> Self {
+        let uniforms = Uniforms::generate(width, height);
+        Self::with_uniforms(device, uniforms_layout, width, height, samples, uniforms)
+    }
+
+    pub fn with_uniforms(
+        device: &wgpu::Device,
+        uniforms_layout: &wgpu::BindGroupLayout,
+        width: u32,
+        height: u32,
+        samples: u32,
+        uniforms: Uniforms,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Peach::RenderTarget"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: TARGET_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                | wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_SRC
+                | wgpu::TextureUsages::COPY_DST,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let msaa_view = (samples > 1).then(|| {
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some("Peach::MultisampledTarget"),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: samples,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: TARGET_FORMAT,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                })
+                .create_view(&wgpu::TextureViewDescriptor::default())
+        });
+
+        let uniforms_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("uniforms buffer"),
+            contents: bytemuck::bytes_of(&uniforms),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("uniforms bind group"),
+            layout: uniforms_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniforms_buf.as_entire_binding(),
+            }],
+        });
+
+        Self {
+            texture,
+            view,
+            width,
+            height,
+            bind_group,
+            msaa_view,
+            msaa_stale: Cell::new(false),
+        }
+    }
+
+    fn extent(&self) -> wgpu::Extent3d {
+        wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: 1,
+        }
+    }
+
+    /// Copy the contents of the target back to the CPU,
+    /// blocking until the GPU has finished.
+    pub fn read_pixels(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> Result<Pixels, RenderError> {
+        // Rows in the readback buffer have to be aligned to
+        // `COPY_BYTES_PER_ROW_ALIGNMENT`.
+        let row_bytes = 4 * self.width;
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_row_bytes = row_bytes + (alignment - row_bytes % alignment) % alignment;
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Peach::Readback"),
+            size: (padded_row_bytes * self.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_row_bytes),
+                    rows_per_image: NonZeroU32::new(self.height),
+                },
+            },
+            self.extent(),
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        pollster::block_on(mapping).map_err(RenderError::BufferMap)?;
+
+        let mut data = Vec::with_capacity((self.width * self.height) as usize);
+        {
+            let view = slice.get_mapped_range();
+            for row in view.chunks(padded_row_bytes as usize) {
+                data.extend_from_slice(row[..row_bytes as usize].as_rgba());
+            }
+        }
+        buffer.unmap();
+
+        Ok(Pixels::from_raw(self.width, self.height, data))
+    }
+
+    /// Replace the contents of the target with `pixels`.
+    pub fn write_pixels(&self, queue: &wgpu::Queue, pixels: &Pixels) -> Result<(), RenderError> {
+        if pixels.width() != self.width || pixels.height() != self.height {
+            return Err(RenderError::PixelsSize);
+        }
+
+        queue.write_texture(
+            self.texture.as_image_copy(),
+            pixels.as_raw().as_bytes(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(4 * self.width),
+                rows_per_image: NonZeroU32::new(self.height),
+            },
+            self.extent(),
+        );
+        self.msaa_stale.set(self.msaa_view.is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! Inspecting tessellated geometry on the CPU
+
+use std::ops::Range;
+
+use crate::types::{BoundingBox, Color, Index, Point, RawVertex, Scalar};
+
+/// The kind of draw command which produced some geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeometryKind {
+    Shape,
+    Image,
+}
+
+/// The geometry produced by a single draw command, as
+/// ranges into [`Tessellation::indices`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandGeometry {
+    pub kind: GeometryKind,
+    /// Indices of the fill triangles, or of the quad for an
+    /// image.
+    pub fill: Range<usize>,
+    /// Indices of the stroke triangles. Always empty for
+    /// images.
+    pub stroke: Range<usize>,
+}
+
+impl CommandGeometry {
+    /// Indices of every triangle drawn by the command.
+    pub fn indices(&self) -> Range<usize> {
+        self.fill.start..self.stroke.end.max(self.fill.end)
+    }
+}
+
+/// A vertex after tessellation, in pixel coordinates with
+/// the transform already applied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vertex {
+    pub position: Point,
+    /// Linear color, as passed to the shader.
+    pub color: Color,
+    /// Texture coordinates, which are zero for shapes.
+    pub uv: Point,
+}
+
+impl From<RawVertex> for Vertex {
+    fn from(raw: RawVertex) -> Self {
+        let [x, y] = raw.position;
+        let [r, g, b, a] = raw.color;
+        let [u, v] = raw.uv;
+
+        Self {
+            position: Point::new(x as Scalar, y as Scalar),
+            color: Color::new(r as Scalar, g as Scalar, b as Scalar, a as Scalar),
+            uv: Point::new(u as Scalar, v as Scalar),
+        }
+    }
+}
+
+/// Everything a [`Graphics`](super::Graphics) would draw,
+/// as triangles. Produced by
+/// [`Graphics::tessellate`](super::Graphics::tessellate)
+/// without needing a GPU, which makes it useful for testing
+/// drawing code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tessellation {
+    vertices: Vec<Vertex>,
+    indices: Vec<Index>,
+    commands: Vec<CommandGeometry>,
+}
+
+impl Tessellation {
+    pub(crate) fn new(
+        vertices: &[RawVertex],
+        indices: Vec<Index>,
+        commands: Vec<CommandGeometry>,
+    ) -> Self {
+        Self {
+            vertices: vertices.iter().copied().map(Vertex::from).collect(),
+            indices,
+            commands,
+        }
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    /// Indices into [`Tessellation::vertices`], three per
+    /// triangle.
+    pub fn indices(&self) -> &[Index] {
+        &self.indices
+    }
+
+    /// The geometry of each draw command, in the order they
+    /// were recorded.
+    pub fn commands(&self) -> &[CommandGeometry] {
+        &self.commands
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    /// Every triangle, in the order they are drawn.
+    pub fn triangles(&self) -> impl Iterator<Item = [Vertex; 3]> + '_ {
+        self.triangles_in(0..self.indices.len())
+    }
+
+    /// The triangles in a range of indices, such as
+    /// [`CommandGeometry::fill`].
+    pub fn triangles_in(&self, indices: Range<usize>) -> impl Iterator<Item = [Vertex; 3]> + '_ {
+        self.indices[indices].chunks_exact(3).map(move |triangle| {
+            [
+                self.vertices[triangle[0] as usize],
+                self.vertices[triangle[1] as usize],
+                self.vertices[triangle[2] as usize],
+            ]
+        })
+    }
+
+    /// Bounds of every triangle, or `None` when nothing is
+    /// drawn.
+    pub fn bounds(&self) -> Option<BoundingBox> {
+        self.bounds_in(0..self.indices.len())
+    }
+
+    /// Bounds of the triangles in a range of indices.
+    pub fn bounds_in(&self, indices: Range<usize>) -> Option<BoundingBox> {
+        let points: Vec<Point> = self.indices[indices]
+            .iter()
+            .map(|i| self.vertices[*i as usize].position)
+            .collect();
+
+        if points.is_empty() {
+            None
+        }
+        else {
+            Some(BoundingBox::from_points(points))
+        }
+    }
+
+    /// Total area covered by the triangles in a range of
+    /// indices. Overlapping triangles are counted twice.
+    pub fn area_in(&self, indices: Range<usize>) -> Scalar {
+        self.triangles_in(indices)
+            .map(|[a, b, c]| {
+                let (a, b, c) = (a.position, b.position, c.position);
+                ((b - a).cross(c - a) / 2.0).abs()
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{AnchorMode, Graphics, Image, Instance, Shape};
+    use crate::types::Angle;
+
+    fn bounds(min: [Scalar; 2], max: [Scalar; 2]) -> Option<BoundingBox> {
+        Some(BoundingBox::new(min.into(), max.into()))
+    }
+
+    #[test]
+    fn rect_fills_two_triangles() {
+        let mut gfx = Graphics::default();
+        gfx.no_stroke();
+        gfx.rect([10.0, 20.0], [30.0, 10.0]);
+
+        let tessellation = gfx.tessellate().unwrap();
+        assert_eq!(tessellation.commands().len(), 1);
+        let command = &tessellation.commands()[0];
+
+        assert_eq!(command.kind, GeometryKind::Shape);
+        assert_eq!(command.fill.len(), 6);
+        assert!(command.stroke.is_empty());
+        assert_eq!(tessellation.triangle_count(), 2);
+        // Rectangles are centered on their position by
+        // default.
+        assert_eq!(tessellation.bounds(), bounds([-5.0, 15.0], [25.0, 25.0]));
+        assert_eq!(tessellation.area_in(command.fill.clone()), 300.0);
+    }
+
+    #[test]
+    fn transforms_are_applied() {
+        let mut gfx = Graphics::default();
+        gfx.anchor_mode(AnchorMode::First);
+        gfx.translate([100.0, 50.0]);
+        gfx.stroke_weight(2.0);
+        gfx.rect([0.0, 0.0], [30.0, 10.0]);
+
+        let tessellation = gfx.tessellate().unwrap();
+        let command = &tessellation.commands()[0];
+
+        assert_eq!(
+            tessellation.bounds_in(command.fill.clone()),
+            bounds([100.0, 50.0], [130.0, 60.0])
+        );
+        assert_eq!(
+            tessellation.bounds_in(command.stroke.clone()),
+            bounds([99.0, 49.0], [131.0, 61.0])
+        );
+    }
+
+    #[test]
+    fn images_are_quads() {
+        let mut gfx = Graphics::default();
+        gfx.image(&Image::new(4, 4), [0.0, 0.0], [8.0, 8.0]);
+
+        let tessellation = gfx.tessellate().unwrap();
+        let command = &tessellation.commands()[0];
+
+        assert_eq!(command.kind, GeometryKind::Image);
+        assert_eq!(command.fill.len(), 6);
+        assert!(command.stroke.is_empty());
+        assert_eq!(tessellation.bounds(), bounds([-4.0, -4.0], [4.0, 4.0]));
+    }
+
+    #[test]
+    fn instances_match_shapes_drawn_by_hand() {
+        let red = Color::new(1.0, 0.0, 0.0, 1.0);
+        let tint = Color::new(0.5, 1.0, 1.0, 0.5);
+        let shape = Shape::new(|gfx| {
+            gfx.fill(red);
+            gfx.stroke_weight(2.0);
+            gfx.rect([0.0, 0.0], [30.0, 10.0]);
+        });
+
+        let mut instanced = Graphics::default();
+        instanced.rotate(Angle::degrees(30.0));
+        instanced.instanced(&shape, &[Instance::at([40.0, 20.0]).with_tint(tint)]);
+
+        // The instance's transform comes before the current
+        // one, and its tint multiplies the shape's colors.
+        let mut by_hand = Graphics::default();
+        by_hand.translate([40.0, 20.0]);
+        by_hand.rotate(Angle::degrees(30.0));
+        by_hand.fill(Color::new(0.5, 0.0, 0.0, 0.5));
+        by_hand.stroke(Color::new(0.0, 0.0, 0.0, 0.5));
+        by_hand.stroke_weight(2.0);
+        by_hand.rect([0.0, 0.0], [30.0, 10.0]);
+
+        let tessellation = instanced.tessellate().unwrap();
+        assert_eq!(tessellation.commands().len(), 1);
+        assert_eq!(tessellation, by_hand.tessellate().unwrap());
+    }
+}
@@ -1,11 +1,15 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
-use winit::dpi::LogicalSize;
+use winit::dpi::{LogicalSize, PhysicalSize};
 use winit::event::{ElementState, KeyboardInput, WindowEvent};
 use winit::window::Window;
 
-use crate::render::{Graphics, Renderer};
+use crate::render::{
+    frame_path, Backend, Canvas, Graphics, Pixels, PresentMode, Recorder, Recording, RenderBackend,
+    RenderError, RendererInitError, Sampling, SaveError, TessellationStats,
+};
 use crate::types::{Color, Fullscreen, Key, Modifiers, MouseButton, Point, Scalar, Size};
 
 #[allow(unused_variables)]
@@ -23,12 +27,128 @@ pub trait Handler {
     fn mouse_released(&mut self, sketch: &mut Sketch, button: MouseButton) {}
 }
 
+/// How long a spin-sleeping sketch spins for before each
+/// frame, to make up for the sleep waking late.
+const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+/// How a sketch with a fixed framerate waits between
+/// frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FramePacing {
+    /// Sleep until the next frame is due, leaving the CPU
+    /// idle. Frames may start a little late, depending on
+    /// how precisely the OS wakes the sketch.
+    #[default]
+    Sleep,
+    /// Sleep until just before the next frame is due, then
+    /// spin until it is. Frames start on time, at the cost
+    /// of keeping a core busy for a moment each frame.
+    SpinSleep,
+}
+
+/// Schedules frames at the sketch's framerate, and counts
+/// frames which were drawn too late.
+pub(super) struct FramePacer {
+    framerate: Option<u32>,
+    pacing: FramePacing,
+    // When the next frame is due, or `None` to draw it
+    // straight away.
+    next_frame: Option<Instant>,
+    missed_frames: u64,
+}
+
+impl FramePacer {
+    fn new(framerate: Option<u32>, pacing: FramePacing) -> Self {
+        Self {
+            framerate,
+            pacing,
+            next_frame: None,
+            missed_frames: 0,
+        }
+    }
+
+    fn frame_delay(&self) -> Option<Duration> {
+        self.framerate
+            .map(|framerate| Duration::from_secs(1) / framerate.max(1))
+    }
+
+    /// When to wake up next, or `None` if the next frame
+    /// should be drawn now.
+    pub fn wait(&self, now: Instant) -> Option<Instant> {
+        let next_frame = self.next_frame?;
+
+        match self.pacing {
+            FramePacing::Sleep => (now < next_frame).then_some(next_frame),
+            FramePacing::SpinSleep => {
+                let wake = next_frame.checked_sub(SPIN_MARGIN).unwrap_or(next_frame);
+                if now < wake {
+                    return Some(wake);
+                }
+
+                while Instant::now() < next_frame {
+                    std::hint::spin_loop();
+                }
+                None
+            },
+        }
+    }
+
+    /// Schedule the frame after one starting at `now`.
+    pub fn start_frame(&mut self, now: Instant) {
+        let delay = match self.frame_delay() {
+            Some(delay) => delay,
+            None => {
+                self.next_frame = None;
+                return;
+            },
+        };
+
+        let due = self.next_frame.unwrap_or(now);
+        // Redraws requested by the window, such as while
+        // resizing, don't move the schedule.
+        if now < due {
+            return;
+        }
+        let late = now - due;
+
+        // Keep to the original schedule when only slightly
+        // late, rather than drifting. Once whole frames have
+        // been missed, start a new schedule instead of
+        // rushing to catch up.
+        self.next_frame = if late >= delay {
+            self.missed_frames += (late.as_nanos() / delay.as_nanos()) as u64;
+            Some(now + delay)
+        }
+        else {
+            Some(due + delay)
+        };
+    }
+}
+
 pub struct Settings<'a> {
     pub title: Option<&'a str>,
     pub size: Size,
     pub decorations: bool,
     pub framerate: Option<u32>,
+    /// How to wait between frames, when `framerate` is
+    /// set.
+    pub frame_pacing: FramePacing,
     pub exit_key: Option<Key>,
+    /// Sampling used for images which don't specify their
+    /// own.
+    pub sampling: Sampling,
+    /// Samples per pixel for multisample anti-aliasing with
+    /// the GPU backend: 1 to disable it, or 4. wgpu can't
+    /// render with 2 or 8 samples, so any other value fails
+    /// to create the renderer with
+    /// [`RendererInitError::MsaaSamples`].
+    pub msaa_samples: u32,
+    /// Whether presenting waits for vsync. Can be changed
+    /// later with [`Sketch::set_present_mode`].
+    pub present_mode: PresentMode,
+    pub backend: Backend,
+    /// Start recording as soon as the sketch starts.
+    pub recording: Option<Recording>,
 }
 
 impl<'a> Default for Settings<'a> {
@@ -38,20 +158,35 @@ impl<'a> Default for Settings<'a> {
             size: Size::new(800.0, 600.0),
             decorations: true,
             framerate: None,
+            frame_pacing: FramePacing::default(),
             exit_key: None,
+            sampling: Sampling::default(),
+            msaa_samples: 1,
+            present_mode: PresentMode::default(),
+            backend: Backend::default(),
+            recording: None,
         }
     }
 }
 
 pub struct Sketch {
-    pub(super) window: Window,
-    pub(super) renderer: Renderer,
+    // `None` when running headless.
+    pub(super) window: Option<Window>,
+    pub(super) renderer: RenderBackend,
     pub(super) clear_color: Option<Color>,
     modifiers: Modifiers,
     running: bool,
-    pub(super) framerate: Option<u32>,
-    pub(super) framerate_dirty: bool,
+    pub(super) pacer: FramePacer,
+    present_mode: PresentMode,
+    pub(super) frame_count: u64,
     start_instant: Instant,
+    // Advances the clock by a fixed amount per frame, for
+    // reproducible tests.
+    fixed_step: Option<Duration>,
+    recorder: Option<Recorder>,
+    // The first error of a recording which failed while the
+    // event loop was running, until it's returned.
+    recording_error: Option<SaveError>,
     exit_key: Option<Key>,
     mouse_position: Point,
     mouse_buttons: HashMap<MouseButton, bool>,
@@ -60,17 +195,55 @@ pub struct Sketch {
 
 impl Sketch {
     pub(super) fn new(window: Window, settings: Settings) -> Self {
-        let renderer = pollster::block_on(Renderer::new(&window)).unwrap();
+        let size = window.inner_size();
+        let renderer = pollster::block_on(RenderBackend::new(
+            settings.backend,
+            Some(&window),
+            size,
+            settings.sampling,
+            settings.msaa_samples,
+            settings.present_mode,
+        ))
+        .unwrap();
+
+        Self::with_renderer(Some(window), renderer, settings)
+    }
+
+    pub(super) fn headless(settings: Settings) -> Result<Self, RendererInitError> {
+        // There's no window to clamp the size, and empty
+        // textures aren't allowed.
+        let size = PhysicalSize::new(
+            (settings.size.width.round() as u32).max(1),
+            (settings.size.height.round() as u32).max(1),
+        );
+        let renderer = pollster::block_on(RenderBackend::new(
+            settings.backend,
+            None,
+            size,
+            settings.sampling,
+            settings.msaa_samples,
+            settings.present_mode,
+        ))?;
+
+        Ok(Self::with_renderer(None, renderer, settings))
+    }
 
+    fn with_renderer(window: Option<Window>, renderer: RenderBackend, settings: Settings) -> Self {
         Self {
             window,
             renderer,
             clear_color: None,
             modifiers: Modifiers::default(),
             running: true,
-            framerate: settings.framerate,
-            framerate_dirty: true,
+            pacer: FramePacer::new(settings.framerate, settings.frame_pacing),
+            present_mode: settings.present_mode,
+            frame_count: 0,
             start_instant: Instant::now(),
+            fixed_step: None,
+            recorder: settings
+                .recording
+                .map(|recording| Recorder::new(recording, Duration::ZERO)),
+            recording_error: None,
             exit_key: settings.exit_key,
             mouse_position: Point::zero(),
             mouse_buttons: HashMap::new(),
@@ -92,33 +265,13 @@ impl Sketch {
                         ..
                     },
                 ..
-            } => {
-                if let Some(exit_key) = self.exit_key {
-                    if exit_key == key {
-                        self.running = false;
-                    }
-                }
-
-                self.keys.insert(key, state == ElementState::Pressed);
-
-                match state {
-                    ElementState::Pressed => handler.key_pressed(self, key),
-                    ElementState::Released => handler.key_released(self, key),
-                }
-            },
+            } => self.key_input(handler, key, state == ElementState::Pressed),
             WindowEvent::CursorMoved { position, .. } => {
-                self.mouse_position = Point::new(position.x as Scalar, position.y as Scalar);
-
-                handler.mouse_moved(self, self.mouse_position);
+                let position = Point::new(position.x as Scalar, position.y as Scalar);
+                self.mouse_moved(handler, position);
             },
             WindowEvent::MouseInput { button, state, .. } => {
-                self.mouse_buttons
-                    .insert(button, state == ElementState::Pressed);
-
-                match state {
-                    ElementState::Pressed => handler.mouse_pressed(self, button),
-                    ElementState::Released => handler.mouse_released(self, button),
-                }
+                self.mouse_input(handler, button, state == ElementState::Pressed)
             },
             WindowEvent::Resized(size) => {
                 self.renderer.resize(size);
@@ -133,12 +286,77 @@ impl Sketch {
         }
     }
 
+    pub(super) fn key_input<H: Handler>(&mut self, handler: &mut H, key: Key, pressed: bool) {
+        if let Some(exit_key) = self.exit_key {
+            if exit_key == key {
+                self.running = false;
+            }
+        }
+
+        self.keys.insert(key, pressed);
+
+        if pressed {
+            handler.key_pressed(self, key);
+        }
+        else {
+            handler.key_released(self, key);
+        }
+    }
+
+    pub(super) fn mouse_moved<H: Handler>(&mut self, handler: &mut H, position: Point) {
+        self.mouse_position = position;
+
+        handler.mouse_moved(self, self.mouse_position);
+    }
+
+    pub(super) fn mouse_input<H: Handler>(
+        &mut self,
+        handler: &mut H,
+        button: MouseButton,
+        pressed: bool,
+    ) {
+        self.mouse_buttons.insert(button, pressed);
+
+        if pressed {
+            handler.mouse_pressed(self, button);
+        }
+        else {
+            handler.mouse_released(self, button);
+        }
+    }
+
     pub(super) fn has_stopped(&self) -> bool {
         !self.running
     }
 
+    /// Number of frames rendered so far, starting at 0
+    /// during the first call to `draw`.
+    pub fn get_frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// How often shapes have been drawn from the
+    /// tessellation cache instead of being re-tessellated,
+    /// since the sketch started.
+    pub fn get_tessellation_stats(&self) -> TessellationStats {
+        self.renderer.tessellation_stats()
+    }
+
+    /// Time since the sketch started. While recording, this
+    /// follows the recording's simulated clock instead of
+    /// the wall clock.
     pub fn get_time_since_start(&self) -> Duration {
-        self.start_instant.elapsed()
+        match (&self.recorder, self.fixed_step) {
+            (Some(recorder), _) => recorder.time(),
+            (None, Some(step)) => step * self.frame_count as u32,
+            (None, None) => self.start_instant.elapsed(),
+        }
+    }
+
+    /// Step the clock by `step` every frame, instead of
+    /// following the wall clock.
+    pub(crate) fn set_fixed_step(&mut self, step: Duration) {
+        self.fixed_step = Some(step);
     }
 
     pub fn get_key(&self, key: Key) -> bool {
@@ -163,7 +381,7 @@ impl Sketch {
     }
 
     pub fn get_size(&self) -> Size {
-        let physical_size = self.window.inner_size();
+        let physical_size = self.renderer.size();
         // let scale_factor = self.window.scale_factor();
         // let logical_size =
         // physical_size.to_logical(scale_factor);
@@ -191,16 +409,338 @@ impl Sketch {
     }
 
     pub fn set_size(&mut self, new_size: Size) {
-        let logical_size = LogicalSize::new(new_size.width, new_size.height);
-        self.window.set_inner_size(logical_size);
+        match &self.window {
+            Some(window) => {
+                let logical_size = LogicalSize::new(new_size.width, new_size.height);
+                window.set_inner_size(logical_size);
+            },
+            None => {
+                let physical_size = PhysicalSize::new(
+                    new_size.width.round() as u32,
+                    new_size.height.round() as u32,
+                );
+                self.renderer.resize(physical_size);
+            },
+        }
+    }
+
+    /// Whether the sketch is running without a window.
+    pub fn is_headless(&self) -> bool {
+        self.window.is_none()
     }
 
     pub fn set_framerate(&mut self, framerate: Option<u32>) {
-        self.framerate_dirty = true;
-        self.framerate = framerate;
+        self.pacer.framerate = framerate;
+        self.pacer.next_frame = None;
+    }
+
+    pub fn set_frame_pacing(&mut self, pacing: FramePacing) {
+        self.pacer.pacing = pacing;
+    }
+
+    /// Number of frames skipped because drawing fell behind
+    /// the framerate, since the sketch started. Always 0
+    /// without a framerate, or when running headless.
+    pub fn get_missed_frames(&self) -> u64 {
+        self.pacer.missed_frames
+    }
+
+    pub fn get_present_mode(&self) -> PresentMode {
+        self.present_mode
+    }
+
+    /// Switch vsync on or off while running, such as to
+    /// measure how fast a sketch can draw. Modes the window
+    /// doesn't support fall back to [`PresentMode::Vsync`].
+    pub fn set_present_mode(&mut self, mode: PresentMode) {
+        self.present_mode = mode;
+        self.renderer.set_present_mode(mode);
     }
 
     pub fn set_fullscreen(&mut self, fullscreen: Option<Fullscreen>) {
-        self.window.set_fullscreen(fullscreen);
+        if let Some(window) = &self.window {
+            window.set_fullscreen(fullscreen);
+        }
+    }
+
+    /// Read back the current frame, including everything
+    /// drawn to `gfx` so far.
+    ///
+    /// Equivalent to Processing's `loadPixels()`.
+    pub fn load_pixels(&mut self, gfx: &mut Graphics) -> Result<Pixels, RenderError> {
+        let flushed = gfx.flush();
+        self.record_flushed(&flushed);
+        self.renderer.draw(flushed)?;
+        self.renderer.read_pixels()
+    }
+
+    /// Replace the current frame with `pixels`. Anything
+    /// drawn to `gfx` before this call is overwritten,
+    /// and anything drawn after is drawn on top.
+    ///
+    /// Equivalent to Processing's `updatePixels()`.
+    pub fn update_pixels(
+        &mut self,
+        gfx: &mut Graphics,
+        pixels: &Pixels,
+    ) -> Result<(), RenderError> {
+        let flushed = gfx.flush();
+        self.record_flushed(&flushed);
+        self.renderer.draw(flushed)?;
+        self.renderer.write_pixels(pixels)
+    }
+
+    /// Save the current frame, including everything drawn
+    /// to `gfx` so far, as a PNG. The last run of `#` in
+    /// `pattern` is replaced with the frame number, padded
+    /// with zeros, so `"frame-####.png"` saves frame 42 as
+    /// `frame-0042.png`. Returns the path that was written.
+    ///
+    /// Equivalent to Processing's `saveFrame()`.
+    pub fn save_frame(&mut self, gfx: &mut Graphics, pattern: &str) -> Result<PathBuf, SaveError> {
+        let pixels = self.load_pixels(gfx)?;
+
+        let path = frame_path(pattern, self.frame_count);
+        pixels.save_png(&path)?;
+
+        Ok(path)
+    }
+
+    /// Start recording every rendered frame. Any recording
+    /// already in progress is finished first, and if that
+    /// fails, or an earlier recording failed, the error is
+    /// returned instead.
+    pub fn start_recording(&mut self, recording: Recording) -> Result<(), SaveError> {
+        self.stop_recording()?;
+
+        self.recorder = Some(Recorder::new(recording, self.get_time_since_start()));
+        Ok(())
+    }
+
+    /// Finish the current recording, if any. The clock
+    /// carries on from the recording's simulated time.
+    ///
+    /// Recordings which fail while the sketch is running
+    /// are stopped, and the error is returned by the next
+    /// call to this.
+    pub fn stop_recording(&mut self) -> Result<(), SaveError> {
+        self.finish_recorder()?;
+
+        match self.recording_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn finish_recorder(&mut self) -> Result<(), SaveError> {
+        if let Some(recorder) = self.recorder.take() {
+            let time = recorder.time();
+            let now = Instant::now();
+            self.start_instant = now.checked_sub(time).unwrap_or(now);
+
+            recorder.finish()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// The error which stopped a recording while the sketch
+    /// was running, if any, until it is returned by
+    /// [`Sketch::stop_recording`].
+    pub fn recording_error(&self) -> Option<&SaveError> {
+        self.recording_error.as_ref()
+    }
+
+    /// Record the draw commands of a frame before it is
+    /// rendered, if a vector recording is in progress.
+    pub(super) fn record_commands(&mut self, gfx: &Graphics) -> Result<(), SaveError> {
+        let size = self.get_size();
+        self.record_with(|recorder| {
+            recorder.record_commands(gfx, size);
+            Ok(())
+        })
+    }
+
+    /// Record commands flushed from `gfx` before the end of
+    /// the frame, so vector recordings don't miss them.
+    fn record_flushed(&mut self, gfx: &Graphics) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_flushed(gfx);
+        }
+    }
+
+    /// Record the frame which was just rendered, if a
+    /// recording is in progress.
+    pub(super) fn record_frame(&mut self) -> Result<(), SaveError> {
+        match &self.recorder {
+            Some(recorder) if recorder.needs_pixels() => {},
+            _ => return Ok(()),
+        }
+
+        let pixels = self.renderer.read_pixels()?;
+        self.record_pixels(&pixels)
+    }
+
+    /// Keep a recording error from the event loop, which
+    /// has nowhere to return it, and stop recording so the
+    /// sketch keeps running.
+    pub(super) fn recording_failed(&mut self, err: SaveError) {
+        // The first error is the useful one, so anything
+        // going wrong while finishing is ignored.
+        let _ = self.finish_recorder();
+        self.recording_error.get_or_insert(err);
+    }
+
+    pub(super) fn record_pixels(&mut self, pixels: &Pixels) -> Result<(), SaveError> {
+        self.record_with(|recorder| recorder.record(pixels))
+    }
+
+    fn record_with<F>(&mut self, record: F) -> Result<(), SaveError>
+    where
+        F: FnOnce(&mut Recorder) -> Result<(), SaveError>,
+    {
+        let recorder = match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => return Ok(()),
+        };
+
+        if let Err(err) = record(recorder) {
+            // The first error is the useful one, so anything
+            // going wrong while finishing is ignored.
+            let _ = self.finish_recorder();
+            return Err(err);
+        }
+
+        if recorder.is_finished() {
+            self.finish_recorder()?;
+        }
+
+        Ok(())
+    }
+
+    /// Render the commands recorded in `gfx` at `scale`
+    /// times the sketch size, for print output. The frame
+    /// is rendered in tiles when it is larger than the
+    /// GPU allows, so the scale can be much larger than
+    /// the screen. Only `gfx` itself is rendered, so
+    /// anything flushed by [`Sketch::load_pixels`] or
+    /// drawn in earlier frames is left out.
+    pub fn render_scaled(&mut self, gfx: &Graphics, scale: u32) -> Result<Pixels, RenderError> {
+        self.renderer.render_scaled(gfx.clone(), scale)
+    }
+
+    /// Create an offscreen canvas, equivalent to
+    /// Processing's `createGraphics()`.
+    pub fn create_graphics(width: u32, height: u32) -> Canvas {
+        Canvas::new(width, height)
+    }
+
+    /// Render `gfx` into `canvas` immediately. The canvas
+    /// can then be drawn in the same frame with
+    /// [`Graphics::image`].
+    pub fn render_canvas(&mut self, canvas: &Canvas, gfx: Graphics) -> Result<(), RenderError> {
+        self.renderer.draw_offscreen(canvas.image(), gfx)
+    }
+
+    /// Read back the current contents of `canvas`.
+    pub fn load_canvas_pixels(&mut self, canvas: &Canvas) -> Result<Pixels, RenderError> {
+        self.renderer.read_offscreen_pixels(canvas.image())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_millis(100);
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn late_frames_keep_the_schedule() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(Some(10), FramePacing::Sleep);
+
+        pacer.start_frame(start);
+        assert_eq!(pacer.next_frame, Some(start + DELAY));
+
+        // Each frame starts a little late, which mustn't add
+        // up over time.
+        for frame in 1..=5 {
+            pacer.start_frame(start + DELAY * frame + ms(7));
+            assert_eq!(pacer.next_frame, Some(start + DELAY * (frame + 1)));
+        }
+        assert_eq!(pacer.missed_frames, 0);
+    }
+
+    #[test]
+    fn missed_frames_are_counted() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(Some(10), FramePacing::Sleep);
+
+        pacer.start_frame(start);
+        let now = start + DELAY + ms(350);
+        pacer.start_frame(now);
+
+        assert_eq!(pacer.missed_frames, 3);
+        assert_eq!(pacer.next_frame, Some(now + DELAY));
+    }
+
+    #[test]
+    fn early_frames_do_not_move_the_schedule() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(Some(10), FramePacing::Sleep);
+
+        pacer.start_frame(start);
+        pacer.start_frame(start + ms(40));
+
+        assert_eq!(pacer.next_frame, Some(start + DELAY));
+        assert_eq!(pacer.missed_frames, 0);
+        assert_eq!(pacer.wait(start + ms(40)), Some(start + DELAY));
+        assert_eq!(pacer.wait(start + DELAY), None);
+    }
+
+    #[test]
+    fn unlimited_framerates_never_wait() {
+        let start = Instant::now();
+        let mut pacer = FramePacer::new(None, FramePacing::Sleep);
+
+        pacer.start_frame(start);
+        assert_eq!(pacer.next_frame, None);
+        assert_eq!(pacer.wait(start), None);
+    }
+
+    #[test]
+    fn recording_errors_are_kept_until_returned() {
+        let settings = Settings {
+            size: Size::new(4.0, 4.0),
+            backend: Backend::Software,
+            ..Default::default()
+        };
+        let mut sketch = Sketch::headless(settings).unwrap();
+
+        let pattern = std::env::temp_dir().join("peach-failed-####.png");
+        let recording = Recording::png(pattern.to_str().unwrap());
+        sketch.start_recording(recording).unwrap();
+
+        sketch.recording_failed(SaveError::GifSize(1, 2));
+        sketch.recording_failed(SaveError::GifSize(3, 4));
+
+        assert!(!sketch.is_recording());
+        assert!(matches!(
+            sketch.recording_error(),
+            Some(SaveError::GifSize(1, 2))
+        ));
+        assert!(matches!(
+            sketch.stop_recording(),
+            Err(SaveError::GifSize(1, 2))
+        ));
+        assert!(sketch.stop_recording().is_ok());
     }
 }
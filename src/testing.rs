@@ -0,0 +1,453 @@
+//! Golden image tests for sketches
+//!
+//! [`TestRun`] runs a [`Handler`] headlessly for a fixed
+//! number of frames, on a clock which advances by exactly
+//! one frame each time, with input replayed from a
+//! [`Script`]. The captured frames can then be compared to
+//! stored PNGs with [`Golden`].
+//!
+//! Runs use the software backend by default, so the output
+//! is the same on every machine, with or without a GPU.
+//!
+//! ```no_run
+//! use peach::prelude::*;
+//! use peach::testing::{Golden, Input, Script, TestRun};
+//!
+//! struct Example;
+//!
+//! impl Handler for Example {
+//!     fn setup(sketch: &mut Sketch) -> Self {
+//!         sketch.set_clear_color(colors::WHITE);
+//!         Self
+//!     }
+//!
+//!     fn draw(&mut self, sketch: &mut Sketch, gfx: &mut Graphics) {
+//!         gfx.square(sketch.get_mouse_position(), 10.0);
+//!     }
+//! }
+//!
+//! let frames = TestRun::new([64.0, 64.0])
+//!     .frames(3)
+//!     .script(Script::new().at(1, Input::MouseMoved([32.0, 32.0].into())))
+//!     .run::<Example>()
+//!     .unwrap();
+//!
+//! Golden::new("tests/golden").assert("example-last", &frames[2]);
+//! ```
+//!
+//! When a golden image doesn't exist yet, the captured
+//! frame is written in its place and the check fails, so it
+//! can be reviewed. Setting the `PEACH_UPDATE_GOLDEN`
+//! environment variable overwrites every golden image
+//! instead of comparing against it.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use rgb::RGBA8;
+use thiserror::Error;
+
+use crate::headless::Headless;
+use crate::render::{Backend, LoadError, Pixels, SaveError};
+use crate::sketch::{Handler, Settings};
+use crate::types::{Key, MouseButton, Point, Size};
+
+/// Environment variable which makes [`Golden`] overwrite
+/// the stored images instead of comparing against them.
+pub const UPDATE_ENV: &str = "PEACH_UPDATE_GOLDEN";
+
+/// An input event replayed by a [`TestRun`].
+#[derive(Debug, Clone, Copy)]
+pub enum Input {
+    KeyPressed(Key),
+    KeyReleased(Key),
+    MouseMoved(Point),
+    MousePressed(MouseButton),
+    MouseReleased(MouseButton),
+}
+
+/// Input events, each delivered just before a given frame
+/// is drawn.
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    events: Vec<(u64, Input)>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deliver `input` before frame `frame` is drawn.
+    /// Events for the same frame are delivered in the
+    /// order they were added.
+    pub fn at(mut self, frame: u64, input: Input) -> Self {
+        self.events.push((frame, input));
+        self
+    }
+
+    fn inputs_at(&self, frame: u64) -> impl Iterator<Item = Input> + '_ {
+        self.events
+            .iter()
+            .filter(move |(at, _)| *at == frame)
+            .map(|(_, input)| *input)
+    }
+}
+
+/// A headless run of a [`Handler`] with deterministic time
+/// and input.
+pub struct TestRun<'a> {
+    settings: Settings<'a>,
+    frames: u64,
+    framerate: u32,
+    script: Script,
+}
+
+impl<'a> TestRun<'a> {
+    /// A run at the given size, using the software backend.
+    pub fn new<S>(size: S) -> Self
+    where
+        S: Into<Size>,
+    {
+        Self::with_settings(Settings {
+            size: size.into(),
+            backend: Backend::Software,
+            ..Default::default()
+        })
+    }
+
+    /// A run with custom settings. Pick
+    /// [`Backend::Software`] for output which doesn't
+    /// depend on the GPU.
+    pub fn with_settings(settings: Settings<'a>) -> Self {
+        Self {
+            settings,
+            frames: 1,
+            framerate: 60,
+            script: Script::new(),
+        }
+    }
+
+    /// Number of frames to run and capture.
+    pub fn frames(mut self, frames: u64) -> Self {
+        self.frames = frames;
+        self
+    }
+
+    /// The rate at which the clock advances, one frame at a
+    /// time.
+    pub fn framerate(mut self, framerate: u32) -> Self {
+        self.framerate = framerate.max(1);
+        self
+    }
+
+    pub fn script(mut self, script: Script) -> Self {
+        self.script = script;
+        self
+    }
+
+    /// Set up `H` and run it, returning every frame.
+    pub fn run<H: Handler>(self) -> Result<Vec<Pixels>> {
+        let mut headless = Headless::new(self.settings)?;
+        headless
+            .sketch()
+            .set_fixed_step(Duration::from_secs(1) / self.framerate);
+
+        let mut handler = headless.setup::<H>();
+
+        let mut output = Vec::with_capacity(self.frames as usize);
+        for frame in 0..self.frames {
+            let sketch = headless.sketch();
+            for input in self.script.inputs_at(frame) {
+                match input {
+                    Input::KeyPressed(key) => sketch.key_input(&mut handler, key, true),
+                    Input::KeyReleased(key) => sketch.key_input(&mut handler, key, false),
+                    Input::MouseMoved(position) => sketch.mouse_moved(&mut handler, position),
+                    Input::MousePressed(button) => sketch.mouse_input(&mut handler, button, true),
+                    Input::MouseReleased(button) => sketch.mouse_input(&mut handler, button, false),
+                }
+            }
+
+            output.push(headless.draw(&mut handler)?);
+        }
+
+        handler.quit();
+        headless.sketch().stop_recording()?;
+
+        Ok(output)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum GoldenError {
+    #[error("No golden image existed, so the frame was written to {0}; check it and run again")]
+    Missing(PathBuf),
+    #[error(
+        "{mismatched} pixels differ from {path} by up to {max_difference}, see {diff} for the \
+         differences"
+    )]
+    Mismatch {
+        path: PathBuf,
+        diff: PathBuf,
+        mismatched: usize,
+        max_difference: u8,
+    },
+    #[error("Frame is {actual:?}, but {path} is {expected:?}")]
+    Size {
+        path: PathBuf,
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    #[error(transparent)]
+    Load(#[from] LoadError),
+    #[error(transparent)]
+    Save(#[from] SaveError),
+}
+
+/// The result of comparing two frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparison {
+    /// Pixels with a channel differing by more than the
+    /// tolerance.
+    pub mismatched: usize,
+    /// Largest difference of any channel, in 8-bit units.
+    pub max_difference: u8,
+}
+
+/// Compare two frames of the same size channel by channel,
+/// ignoring differences up to `tolerance`.
+pub fn compare(actual: &Pixels, expected: &Pixels, tolerance: u8) -> Comparison {
+    let mut comparison = Comparison {
+        mismatched: 0,
+        max_difference: 0,
+    };
+
+    for (a, b) in actual.as_raw().iter().zip(expected.as_raw()) {
+        let difference = channel_difference(*a, *b);
+        comparison.max_difference = comparison.max_difference.max(difference);
+        if difference > tolerance {
+            comparison.mismatched += 1;
+        }
+    }
+
+    comparison
+}
+
+fn channel_difference(a: RGBA8, b: RGBA8) -> u8 {
+    let d = |x: u8, y: u8| (x as i16 - y as i16).unsigned_abs() as u8;
+    d(a.r, b.r)
+        .max(d(a.g, b.g))
+        .max(d(a.b, b.b))
+        .max(d(a.a, b.a))
+}
+
+/// Golden PNGs stored in a directory.
+pub struct Golden {
+    dir: PathBuf,
+    tolerance: u8,
+    max_mismatched: usize,
+}
+
+impl Golden {
+    /// Compare against PNGs in `dir`. By default, channels
+    /// may differ by 1 to allow for rounding, and no pixel
+    /// may differ by more.
+    pub fn new<P>(dir: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self {
+            dir: dir.into(),
+            tolerance: 1,
+            max_mismatched: 0,
+        }
+    }
+
+    /// Largest per-channel difference, in 8-bit units,
+    /// which is still considered a match.
+    pub fn with_tolerance(mut self, tolerance: u8) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Number of pixels which may exceed the tolerance
+    /// before the frame is considered different.
+    pub fn with_max_mismatched(mut self, max_mismatched: usize) -> Self {
+        self.max_mismatched = max_mismatched;
+        self
+    }
+
+    fn path(&self, name: &str, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}{}.png", name, suffix))
+    }
+
+    /// Compare `pixels` to the golden image `name`. On a
+    /// mismatch, the frame is written next to it as
+    /// `<name>.actual.png`, along with `<name>.diff.png`
+    /// highlighting the differences in red.
+    pub fn check(&self, name: &str, pixels: &Pixels) -> Result<(), GoldenError> {
+        let path = self.path(name, "");
+        let actual = self.path(name, ".actual");
+
+        if std::env::var_os(UPDATE_ENV).is_some() {
+            std::fs::create_dir_all(&self.dir).map_err(SaveError::from)?;
+            pixels.save_png(&path)?;
+            return Ok(());
+        }
+
+        if !path.exists() {
+            std::fs::create_dir_all(&self.dir).map_err(SaveError::from)?;
+            pixels.save_png(&path)?;
+            return Err(GoldenError::Missing(path));
+        }
+
+        let expected = Pixels::load_png(&path)?;
+        if (expected.width(), expected.height()) != (pixels.width(), pixels.height()) {
+            pixels.save_png(&actual)?;
+            return Err(GoldenError::Size {
+                path,
+                expected: (expected.width(), expected.height()),
+                actual: (pixels.width(), pixels.height()),
+            });
+        }
+
+        let comparison = compare(pixels, &expected, self.tolerance);
+        if comparison.mismatched > self.max_mismatched {
+            let diff = self.path(name, ".diff");
+            pixels.save_png(&actual)?;
+            diff_image(pixels, &expected, self.tolerance).save_png(&diff)?;
+
+            return Err(GoldenError::Mismatch {
+                path,
+                diff,
+                mismatched: comparison.mismatched,
+                max_difference: comparison.max_difference,
+            });
+        }
+
+        // Clean up output from earlier failures.
+        for stale in [actual, self.path(name, ".diff")] {
+            let _ = std::fs::remove_file(stale);
+        }
+
+        Ok(())
+    }
+
+    /// Like [`Golden::check`], but panics on any failure.
+    #[track_caller]
+    pub fn assert(&self, name: &str, pixels: &Pixels) {
+        if let Err(err) = self.check(name, pixels) {
+            panic!("Golden image `{}` failed: {}", name, err);
+        }
+    }
+
+    /// The directory the golden images are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+/// A faded grayscale copy of `expected`, with pixels which
+/// differ beyond `tolerance` drawn in red.
+fn diff_image(actual: &Pixels, expected: &Pixels, tolerance: u8) -> Pixels {
+    let data = actual
+        .as_raw()
+        .iter()
+        .zip(expected.as_raw())
+        .map(|(a, b)| {
+            if channel_difference(*a, *b) > tolerance {
+                RGBA8::new(255, 0, 0, 255)
+            }
+            else {
+                let luma = (b.r as u32 * 3 + b.g as u32 * 6 + b.b as u32) / 10;
+                let faded = (128 + luma / 2) as u8;
+                RGBA8::new(faded, faded, faded, 255)
+            }
+        })
+        .collect();
+
+    Pixels::from_raw(actual.width(), actual.height(), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: u32, height: u32, color: RGBA8) -> Pixels {
+        Pixels::from_raw(width, height, vec![color; (width * height) as usize])
+    }
+
+    #[test]
+    fn channel_difference_is_largest_channel() {
+        let a = RGBA8::new(10, 20, 30, 40);
+        assert_eq!(channel_difference(a, a), 0);
+        assert_eq!(channel_difference(a, RGBA8::new(12, 17, 30, 40)), 3);
+        assert_eq!(channel_difference(RGBA8::new(12, 17, 30, 40), a), 3);
+        assert_eq!(
+            channel_difference(RGBA8::new(0, 0, 0, 0), RGBA8::new(0, 0, 0, 255)),
+            255
+        );
+    }
+
+    #[test]
+    fn compare_allows_differences_up_to_tolerance() {
+        let expected = solid(2, 2, RGBA8::new(100, 100, 100, 255));
+        let mut actual = expected.clone();
+        actual.as_raw_mut()[0] = RGBA8::new(101, 100, 100, 255);
+        actual.as_raw_mut()[1] = RGBA8::new(100, 102, 100, 255);
+
+        assert_eq!(
+            compare(&actual, &expected, 2),
+            Comparison {
+                mismatched: 0,
+                max_difference: 2,
+            }
+        );
+        assert_eq!(
+            compare(&actual, &expected, 1),
+            Comparison {
+                mismatched: 1,
+                max_difference: 2,
+            }
+        );
+        assert_eq!(
+            compare(&actual, &expected, 0),
+            Comparison {
+                mismatched: 2,
+                max_difference: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn diff_image_marks_mismatches_in_red() {
+        let expected = solid(2, 1, RGBA8::new(0, 0, 0, 255));
+        let mut actual = expected.clone();
+        actual.as_raw_mut()[0] = RGBA8::new(2, 0, 0, 255);
+        actual.as_raw_mut()[1] = RGBA8::new(1, 0, 0, 255);
+
+        let diff = diff_image(&actual, &expected, 1);
+        assert_eq!(diff.as_raw()[0], RGBA8::new(255, 0, 0, 255));
+        assert_eq!(diff.as_raw()[1], RGBA8::new(128, 128, 128, 255));
+    }
+
+    #[test]
+    fn script_delivers_inputs_in_order() {
+        let script = Script::new()
+            .at(1, Input::KeyPressed(Key::A))
+            .at(0, Input::MousePressed(MouseButton::Left))
+            .at(1, Input::KeyReleased(Key::A));
+
+        let at = |frame| {
+            script
+                .inputs_at(frame)
+                .map(|input| format!("{:?}", input))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(at(0), ["MousePressed(Left)"]);
+        assert_eq!(at(1), ["KeyPressed(A)", "KeyReleased(A)"]);
+        assert!(at(2).is_empty());
+    }
+}
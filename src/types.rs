@@ -10,11 +10,57 @@ pub use winit::window::Fullscreen;
 pub(crate) struct RawVertex {
     pub position: [GpuScalar; 2],
     pub color: [GpuScalar; 4],
+    pub uv: [GpuScalar; 2],
+    // Index into the transforms uploaded with the vertices.
+    pub transform: u32,
+    // Direction from `position` towards the edge of an
+    // anti-aliased stroke, which is extruded in the vertex
+    // shader. Zero for other geometry.
+    pub normal: [GpuScalar; 2],
+    // Side of the stroke the vertex is on (1 or -1) and the
+    // stroke's half width, which is negative for hairlines.
+    // Zero for geometry which isn't feathered.
+    pub stroke: [GpuScalar; 2],
 }
 
 unsafe impl bytemuck::Pod for RawVertex {}
 unsafe impl bytemuck::Zeroable for RawVertex {}
 
+/// A [`Transform`] laid out for a storage buffer, with the
+/// linear part in `linear`, column by column, and the
+/// translation in the first two elements of `translation`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub(crate) struct RawTransform {
+    pub linear: [GpuScalar; 4],
+    pub translation: [GpuScalar; 4],
+}
+
+unsafe impl bytemuck::Pod for RawTransform {}
+unsafe impl bytemuck::Zeroable for RawTransform {}
+
+/// Per-instance vertex data.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub(crate) struct RawInstance {
+    pub transform: RawTransform,
+    pub tint: [GpuScalar; 4],
+}
+
+unsafe impl bytemuck::Pod for RawInstance {}
+unsafe impl bytemuck::Zeroable for RawInstance {}
+
+impl From<Transform> for RawTransform {
+    fn from(transform: Transform) -> Self {
+        let t = transform.cast::<GpuScalar>();
+
+        Self {
+            linear: [t.m11, t.m12, t.m21, t.m22],
+            translation: [t.m31, t.m32, 0.0, 0.0],
+        }
+    }
+}
+
 // Common types
 #[cfg(feature = "force_f32")]
 pub type Scalar = f32;
@@ -0,0 +1,129 @@
+use peach::prelude::*;
+use peach::testing::{Golden, Input, Script, TestRun};
+
+fn golden() -> Golden {
+    Golden::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"))
+}
+
+struct Shapes;
+
+impl Handler for Shapes {
+    fn setup(sketch: &mut Sketch) -> Self {
+        sketch.set_clear_color(colors::WHITE);
+        Self
+    }
+
+    fn draw(&mut self, sketch: &mut Sketch, gfx: &mut Graphics) {
+        gfx.fill(colors::RED);
+        gfx.no_stroke();
+        gfx.rect([8.0, 8.0], [24.0, 16.0]);
+
+        gfx.fill(colors::BLUE);
+        gfx.stroke(colors::BLACK);
+        gfx.stroke_weight(2.0);
+        gfx.rect([38.0, 10.0], [18.0, 12.0]);
+
+        gfx.scoped(|gfx| {
+            gfx.anchor_mode(AnchorMode::Center);
+            gfx.translate([32.0, 44.0]);
+            gfx.rotate(Angle::degrees(30.0));
+            gfx.fill(colors::GREEN);
+            gfx.square(Point::zero(), 16.0);
+        });
+
+        gfx.no_fill();
+        gfx.stroke(colors::BLUE);
+        gfx.square(sketch.get_mouse_position(), 6.0);
+    }
+}
+
+#[test]
+fn software_shapes() {
+    let frames = TestRun::new([64.0, 64.0])
+        .frames(2)
+        .script(Script::new().at(1, Input::MouseMoved([50.0, 50.0].into())))
+        .run::<Shapes>()
+        .unwrap();
+
+    golden().assert("software-shapes-0", &frames[0]);
+    golden().assert("software-shapes-1", &frames[1]);
+}
+
+/// Images edited between frames, drawn both at full size
+/// and shrunk with mipmaps, along with a canvas rendered
+/// every frame. Edits for frames before `START` are made up
+/// front, so a run starting part way through can be
+/// compared with one which drew every frame.
+struct Images<const START: u64> {
+    checker: Image,
+    canvas: Canvas,
+}
+
+impl<const START: u64> Images<START> {
+    fn edit(&mut self, frame: u64) {
+        let i = frame as u32;
+        self.checker.set(i, 7 - i, colors::RED);
+    }
+}
+
+impl<const START: u64> Handler for Images<START> {
+    fn setup(sketch: &mut Sketch) -> Self {
+        sketch.set_clear_color(colors::WHITE);
+
+        let mut checker = Image::new(8, 8).with_sampling(Sampling::smooth());
+        for y in 0..8 {
+            for x in 0..8 {
+                if (x + y) % 2 == 0 {
+                    checker.set(x, y, colors::BLUE);
+                }
+            }
+        }
+
+        let mut images = Self {
+            checker,
+            canvas: Sketch::create_graphics(16, 16),
+        };
+        for frame in 0..START {
+            images.edit(frame);
+        }
+        images
+    }
+
+    fn draw(&mut self, sketch: &mut Sketch, gfx: &mut Graphics) {
+        let frame = START + sketch.get_frame_count();
+        self.edit(frame);
+
+        let mut canvas = Canvas::graphics(Some(colors::BLACK));
+        canvas.fill(colors::GREEN);
+        canvas.no_stroke();
+        canvas.square([4.0 + frame as Scalar * 2.0, 8.0], 6.0);
+        sketch.render_canvas(&self.canvas, canvas).unwrap();
+
+        gfx.anchor_mode(AnchorMode::First);
+        gfx.image(&self.checker, [4.0, 4.0], [32.0, 32.0]);
+        gfx.image(&self.checker, [40.0, 4.0], [4.0, 4.0]);
+        gfx.image(
+            &self.checker.clone().with_sampling(Sampling::pixelated()),
+            [40.0, 12.0],
+            [16.0, 16.0],
+        );
+        gfx.image(self.canvas.image(), [4.0, 40.0], [16.0, 16.0]);
+    }
+}
+
+#[test]
+fn software_images() {
+    let frames = TestRun::new([64.0, 64.0])
+        .frames(4)
+        .run::<Images<0>>()
+        .unwrap();
+
+    for (i, frame) in frames.iter().enumerate() {
+        golden().assert(&format!("software-images-{}", i), frame);
+    }
+
+    // Decoded images are cached between frames, which
+    // mustn't change what is drawn.
+    let fresh = TestRun::new([64.0, 64.0]).run::<Images<3>>().unwrap();
+    assert_eq!(fresh[0], frames[3]);
+}